@@ -1,67 +1,125 @@
-use std::path::PathBuf;
-
-use anyhow::Result;
-use async_openai::types::{
-    ChatCompletionRequestSystemMessageArgs, CreateSpeechRequestArgs, SpeechModel, Voice,
-};
-use async_openai::{
-    types::{ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs},
-    Client as LLM_Client,
-};
-use chrono::{Duration, Utc};
-use teloxide::prelude::Message;
-
-pub(crate) async fn llm_processing(system_role: String, request: String) -> Result<String> {
-    let client = LLM_Client::new();
-
-    let llm_request = CreateChatCompletionRequestArgs::default()
-        .max_tokens(8192u32)
-        .model("gpt-4o-2024-08-06")
-        .temperature(0.4)
-        .messages([
-            ChatCompletionRequestSystemMessageArgs::default()
-                .content(system_role.as_str())
-                .build()?
-                .into(),
-            ChatCompletionRequestUserMessageArgs::default()
-                .content(request)
-                .build()?
-                .into(),
-        ])
-        .build()?;
-
-    let response = client.chat().create(llm_request).await?;
-
-    if let Some(choice) = response.choices.get(0) {
-        let content = choice.message.content.clone().unwrap_or_else(|| {
-            "Извини, я не смог понять твой вопрос. Пожалуйста, попробуй снова.".to_string()
-        });
-        Ok(content)
-    } else {
-        Ok("Извини, я не смог понять твой вопрос. Пожалуйста, попробуй снова.".to_string())
-    }
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use futures::future::try_join_all;
+use log::info;
+use tokio::process::Command;
+
+use crate::pipeline::PodcastScript;
+use crate::speech::{split_script, SpeechSynthesizer};
+
+/// OpenAI speech takes at most 4096 characters per request.
+const MAX_CHUNK_CHARS: usize = 4000;
+const PODCAST_BITRATE: &str = "64k";
+
+/// The podcast as it's sent to the user: an OGG/Opus voice message.
+pub(crate) struct PodcastAudio {
+    pub path: PathBuf,
+    pub duration: Duration,
 }
 
-pub(crate) async fn text_to_speech(text: String, msg: Message) -> Result<PathBuf> {
-    let user_id = msg.from.as_ref().map(|user| user.id.0).unwrap_or(0);
+/// Reads the script chunk by chunk, all chunks at once, and stitches the results into one file.
+pub(crate) async fn text_to_speech(
+    speech: &dyn SpeechSynthesizer,
+    script: &PodcastScript,
+    user_id: u64,
+) -> Result<PodcastAudio> {
     let user_tmp_dir = format!("tmp/{}", user_id);
+    fs::create_dir_all(&user_tmp_dir)?;
 
-    let now = Utc::now();
-    let utc_plus_3 = now + Duration::hours(3);
-    let date_only = utc_plus_3.date_naive();
+    // The time keeps apart the files of podcasts made at once
+    let run = script.created_at.format("%Y-%m-%d_%H%M%S_%3f");
 
-    let client = LLM_Client::new();
-    let request = CreateSpeechRequestArgs::default()
-        .input(&text)
-        .voice(Voice::Onyx)
-        .model(SpeechModel::Tts1Hd)
-        .speed(1.3)
-        .build()?;
+    let chunks = split_script(&script.text, MAX_CHUNK_CHARS);
+    info!("Synthesizing the podcast in {} chunks", chunks.len());
 
-    let response = client.audio().speech(request).await?;
+    let chunk_paths: Vec<PathBuf> = (0..chunks.len())
+        .map(|index| {
+            PathBuf::from(format!(
+                "{}/{}_chunk_{:03}.{}",
+                user_tmp_dir,
+                run,
+                index,
+                speech.file_extension()
+            ))
+        })
+        .collect();
+
+    let synthesized = try_join_all(
+        chunks
+            .iter()
+            .zip(&chunk_paths)
+            .map(|(chunk, path)| speech.synthesize(chunk, path)),
+    )
+    .await;
+
+    let audio_file_path = PathBuf::from(format!("{}/{}_audio_podcast.ogg", user_tmp_dir, run));
+    let stitched = match synthesized {
+        Ok(_) => concat_audio(&chunk_paths, &audio_file_path).await,
+        Err(e) => Err(e),
+    };
+
+    for path in &chunk_paths {
+        let _ = fs::remove_file(path);
+    }
+    stitched?;
+
+    let duration = audio_duration(&audio_file_path).await?;
+    info!("Podcast {:?} is {} seconds long", audio_file_path, duration.as_secs());
+
+    Ok(PodcastAudio {
+        path: audio_file_path,
+        duration,
+    })
+}
+
+/// Joins the chunks with ffmpeg and encodes the result as OGG/Opus, the format of voice messages.
+async fn concat_audio(chunk_paths: &[PathBuf], output: &Path) -> Result<()> {
+    let list_path = output.with_extension("ffconcat");
+    let list: String = chunk_paths
+        .iter()
+        .map(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            format!("file '{}'\n", name.replace('\'', "'\\''"))
+        })
+        .collect();
+    fs::write(&list_path, list)?;
+
+    let result = Command::new("ffmpeg")
+        .args(["-y", "-v", "error", "-f", "concat", "-safe", "0", "-i"])
+        .arg(&list_path)
+        .args(["-c:a", "libopus", "-b:a", PODCAST_BITRATE])
+        .arg(output)
+        .output()
+        .await
+        .context("Failed to start ffmpeg");
+    let _ = fs::remove_file(&list_path);
+
+    let result = result?;
+    if !result.status.success() {
+        bail!(
+            "ffmpeg exited with {}: {}",
+            result.status,
+            String::from_utf8_lossy(&result.stderr)
+        );
+    }
+    Ok(())
+}
 
-    let audio_file_path = format!("{}/{}_audio_podcast.mp3", user_tmp_dir, date_only);
-    response.save(audio_file_path.clone()).await?;
+async fn audio_duration(path: &Path) -> Result<Duration> {
+    let result = Command::new("ffprobe")
+        .args(["-v", "error", "-show_entries", "format=duration"])
+        .args(["-of", "default=noprint_wrappers=1:nokey=1"])
+        .arg(path)
+        .output()
+        .await
+        .context("Failed to start ffprobe")?;
 
-    Ok(PathBuf::from(audio_file_path))
+    let seconds: f64 = String::from_utf8_lossy(&result.stdout)
+        .trim()
+        .parse()
+        .with_context(|| format!("ffprobe returned no duration for {:?}", path))?;
+    Ok(Duration::from_secs_f64(seconds))
 }
@@ -1,26 +1,64 @@
-use crate::ai_utils::llm_processing;
+use crate::client_pool::ClientPool;
 use crate::common_utils::load_localization;
+use crate::llm::{complete_structured, LlmProvider, StructuredOutput};
+use crate::telegram::{telegram_request, throttle};
 use crate::AuthStages;
-use grammers_client::{Client, Config, SignInError};
-use grammers_session::Session;
+use grammers_client::SignInError;
 use log::info;
+use serde::Deserialize;
+use serde_json::{json, Value};
 use std::fs;
-use std::path::Path;
 use std::sync::Arc;
 use teloxide::prelude::{Message, Requester};
 use teloxide::Bot;
 
+/// The login code as the model extracted it from what the user typed.
+#[derive(Debug, Deserialize)]
+pub(crate) struct LoginCode {
+    code: String,
+}
+
+impl StructuredOutput for LoginCode {
+    const NAME: &'static str = "login_code";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": { "code": { "type": "string" } },
+            "required": ["code"],
+            "additionalProperties": false,
+        })
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.code.len() == 5 && self.code.chars().all(|c| c.is_ascii_digit()) {
+            Ok(())
+        } else {
+            Err(format!("\"{}\" is not a five-digit code", self.code))
+        }
+    }
+
+    /// The digits the user typed, a made up code when they aren't a code.
+    fn mock_example(request: &str) -> Value {
+        let digits: String = request.chars().filter(char::is_ascii_digit).collect();
+        let code = if digits.len() == 5 { digits } else { "12345".to_string() };
+        json!({ "code": code })
+    }
+}
+
 pub(crate) async fn session_file_creation(
     bot: Bot,
     msg: Message,
     state: &mut AuthStages,
+    clients: &ClientPool,
+    llm: &dyn LlmProvider,
     language_code: String,
 ) -> anyhow::Result<()> {
     let client = state.client.as_ref().unwrap();
 
     let localization = load_localization(&language_code);
 
-    if !client.is_authorized().await? {
+    if !telegram_request!(true, client.is_authorized())? {
         info!("State: awaiting_phone_number");
 
         if state.awaiting_phone_number {
@@ -33,6 +71,7 @@ pub(crate) async fn session_file_creation(
                     state.awaiting_phone_number, state.awaiting_passcode, state.awaiting_2fa
                 );
 
+                throttle().await;
                 let token = client.request_login_code(phone).await?;
                 state.token = Some(Arc::from(token));
 
@@ -50,8 +89,9 @@ pub(crate) async fn session_file_creation(
                 let system_role = fs::read_to_string("common_res/system_role_3.txt")
                     .map_err(|e| format!("Failed to read 'system role': {}", e))
                     .unwrap();
-                let code_result = llm_processing(system_role, data_to_edit).await?;
-                state.passcode = Some(code_result.to_string());
+                let code_result: LoginCode =
+                    complete_structured(llm, &system_role, &data_to_edit).await?;
+                state.passcode = Some(code_result.code);
             }
             state.awaiting_passcode = false;
 
@@ -61,6 +101,7 @@ pub(crate) async fn session_file_creation(
 
             if let Some(token) = state.token.as_ref() {
                 let code = state.passcode.as_ref().unwrap();
+                throttle().await;
                 match client.sign_in(&token, code).await {
                     Ok(_) => {
                         let message = localization["session_file_creation_fn"]["authorized"]
@@ -68,9 +109,7 @@ pub(crate) async fn session_file_creation(
                             .unwrap_or("Default message")
                             .to_string();
                         bot.send_message(msg.chat.id, message).await?;
-                        client
-                            .session()
-                            .save_to_file(format!("users_sessions/{}.session", msg.chat.id))?;
+                        clients.save_session(client, msg.chat.id.0 as u64)?;
                     }
                     Err(SignInError::PasswordRequired(password_token)) => {
                         let hint = password_token.hint().unwrap_or_else(|| {
@@ -105,6 +144,7 @@ pub(crate) async fn session_file_creation(
 
             if let Some(password) = msg.text() {
                 if let Some(password_token) = &state.password_token {
+                    throttle().await;
                     match client
                         .check_password(password_token.clone(), password)
                         .await
@@ -121,9 +161,7 @@ pub(crate) async fn session_file_creation(
                             info!("State change 4: awaiting_phone_number = {}, awaiting_passcode = {}, awaiting_2fa = {}",
                     state.awaiting_phone_number, state.awaiting_passcode, state.awaiting_2fa
                 );
-                            client
-                                .session()
-                                .save_to_file(format!("users_sessions/{}.session", msg.chat.id))?;
+                            clients.save_session(client, msg.chat.id.0 as u64)?;
                         }
                         Err(e) => {
                             bot.send_message(
@@ -159,76 +197,122 @@ pub(crate) async fn authentication(
     msg: Message,
     state: &mut AuthStages,
     user_id: u64,
-    api_id: i32,
-    api_hash: String,
+    clients: &ClientPool,
     language_code: &str,
 ) -> Result<bool, anyhow::Error> {
     info!("Authentication fn: Authentication started...");
     let localization = load_localization(language_code);
 
-    let user_sessions_dir = Path::new("users_sessions");
-    if !user_sessions_dir.exists() {
-        fs::create_dir(user_sessions_dir).expect("Failed to create 'users_sessions' folder");
+    if !clients.session_path(user_id).exists() {
+        info!("Authentication fn: no client and no session found...");
     }
 
-    let session_file = format!("users_sessions/{}.session", user_id);
-    let session_path = Path::new(&session_file);
+    let client = clients.get(user_id).await?;
 
-    if session_path.exists() {
-        let client = Client::connect(Config {
-            session: Session::load_file_or_create(session_path)?,
-            api_id,
-            api_hash,
-            params: Default::default(),
-        })
-        .await?;
+    info!("Authentication fn: Client initialized");
 
-        state.client = Some(client);
+    if telegram_request!(true, client.is_authorized())? {
+        // The pool hands out the client from now on, a left over login one would go stale
+        state.client = None;
+        let message = localization["authentication_fn"]["authorized"]
+            .as_str()
+            .unwrap_or("Default message")
+            .to_string();
+        bot.send_message(msg.chat.id, message).await?;
+        return Ok(true);
+    }
 
-        info!("Authentication fn: Client initialized with existing session");
+    // Kept for the login only, the tokens it gets are bound to this connection
+    state.client = Some(client);
+    state.awaiting_phone_number = true;
+    let message = localization["authentication_fn"]["awaiting_phone"]
+        .as_str()
+        .unwrap_or("Default message")
+        .to_string();
+    bot.send_message(msg.chat.id, message).await?;
+    Ok(false)
+}
 
-        if let Some(client) = state.client.as_ref() {
-            if client.is_authorized().await? {
-                let message = localization["authentication_fn"]["authorized"]
-                    .as_str()
-                    .unwrap_or("Default message")
-                    .to_string();
-                bot.send_message(msg.chat.id, message).await?;
-                return Ok(true);
-            } else {
-                state.awaiting_phone_number = true;
-                let message = localization["authentication_fn"]["awaiting_phone"]
-                    .as_str()
-                    .unwrap_or("Default message")
-                    .to_string();
-                bot.send_message(msg.chat.id, message).await?;
-                return Ok(false);
-            }
-        }
-    } else {
-        info!("Authentication fn: no client and no session found...");
+pub(crate) async fn restore_interrupted_login(
+    bot: Bot,
+    msg: Message,
+    state: &mut AuthStages,
+    user_id: u64,
+    clients: &ClientPool,
+    language_code: &str,
+) -> Result<bool, anyhow::Error> {
+    info!("Restore login fn: Resuming authentication interrupted by a restart...");
+    let localization = load_localization(language_code);
 
-        let client = Client::connect(Config {
-            session: Session::load_file_or_create(session_path)?,
-            api_id,
-            api_hash,
-            params: Default::default(),
-        })
-        .await?;
+    let client = clients.get(user_id).await?;
 
+    if state.awaiting_phone_number {
         state.client = Some(client);
+        return Ok(true);
+    }
 
-        info!("Authentication fn: Client initialized with a new session");
+    // Login and password tokens can't be persisted, so the code has to be requested again
+    if state.awaiting_passcode {
+        if let Some(phone) = state.phone_number.clone() {
+            throttle().await;
+            let token = client.request_login_code(&phone).await?;
+            state.token = Some(Arc::from(token));
+            state.client = Some(client);
+
+            let message = localization["session_file_creation_fn"]["awaiting_passcode"]
+                .as_str()
+                .unwrap_or("Default message")
+                .to_string();
+            bot.send_message(msg.chat.id, message).await?;
+            return Ok(false);
+        }
+    }
 
-        state.awaiting_phone_number = true;
+    state.client = Some(client);
+    state.awaiting_phone_number = true;
+    state.awaiting_passcode = false;
+    state.awaiting_2fa = false;
 
-        let message = localization["authentication_fn"]["awaiting_phone"]
-            .as_str()
-            .unwrap_or("Default message")
-            .to_string();
-        bot.send_message(msg.chat.id, message).await?;
-        return Ok(false);
-    }
+    let message = localization["session_file_creation_fn"]["login_interrupted"]
+        .as_str()
+        .unwrap_or("Default message")
+        .to_string();
+    bot.send_message(msg.chat.id, message).await?;
 
     Ok(false)
 }
+
+/// Logs the account out on Telegram's side and removes the session file,
+/// returns `false` if there was no authorized session to end.
+pub(crate) async fn sign_out(
+    state: &mut AuthStages,
+    user_id: u64,
+    clients: &ClientPool,
+) -> Result<bool, anyhow::Error> {
+    let session_path = clients.session_path(user_id);
+
+    let client = match state.client.take() {
+        Some(client) => Some(client),
+        None if session_path.exists() => Some(clients.get(user_id).await?),
+        None => None,
+    };
+
+    let mut signed_out = false;
+    if let Some(client) = client {
+        if telegram_request!(true, client.is_authorized())? {
+            throttle().await;
+            client.sign_out().await?;
+            signed_out = true;
+            info!("Sign out fn: Session of {} ended", user_id);
+        }
+    }
+
+    // Forgotten before the file is removed, so that the pool doesn't write it back
+    clients.forget(user_id);
+    if session_path.exists() {
+        fs::remove_file(&session_path)?;
+        info!("Sign out fn: Session file {:?} removed", session_path);
+    }
+
+    Ok(signed_out)
+}
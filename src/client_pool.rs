@@ -0,0 +1,161 @@
+use crate::common_utils::positive_from_env;
+use crate::session_store::SessionStore;
+use crate::telegram::throttle;
+use grammers_client::{Client, Config};
+use log::info;
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{interval, Instant};
+
+/// A client nobody asked for this long is disconnected, `CLIENT_IDLE_MINUTES` env var.
+const DEFAULT_CLIENT_IDLE_MINUTES: u64 = 30;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct Slot {
+    client: Option<Client>,
+    last_used: Option<Instant>,
+}
+
+/// Owns one Telegram connection per user, shared by the login flow and every podcast of the user.
+/// A broken connection is replaced on the next request, and idle ones are closed with their
+/// sessions saved to disk.
+pub(crate) struct ClientPool {
+    api_id: i32,
+    api_hash: String,
+    sessions: SessionStore,
+    idle: Duration,
+    /// A lock per user, so connecting one user doesn't hold up the others.
+    slots: Mutex<HashMap<u64, Arc<tokio::sync::Mutex<Slot>>>>,
+}
+
+impl ClientPool {
+    /// Uses the `TELEGRAM_API_ID` and `TELEGRAM_API_HASH` env vars, starts closing idle clients.
+    pub(crate) fn from_env(sessions: SessionStore) -> Arc<Self> {
+        let api_id: i32 = env::var("TELEGRAM_API_ID")
+            .expect("API_ID not set")
+            .parse()
+            .expect("API_ID must be a number");
+        let api_hash = env::var("TELEGRAM_API_HASH").expect("API_HASH not set");
+        let idle_minutes = positive_from_env("CLIENT_IDLE_MINUTES", DEFAULT_CLIENT_IDLE_MINUTES);
+
+        let pool = Arc::new(Self {
+            api_id,
+            api_hash,
+            sessions,
+            idle: Duration::from_secs(idle_minutes * 60),
+            slots: Mutex::new(HashMap::new()),
+        });
+
+        let sweeper = pool.clone();
+        tokio::spawn(async move {
+            let mut ticks = interval(SWEEP_INTERVAL);
+            loop {
+                ticks.tick().await;
+                sweeper.close_idle().await;
+            }
+        });
+
+        pool
+    }
+
+    pub(crate) fn session_path(&self, user_id: u64) -> PathBuf {
+        self.sessions.path(user_id)
+    }
+
+    /// The connected client of the user, a new connection is made from the session file when
+    /// there's none yet or the old one stopped answering.
+    pub(crate) async fn get(&self, user_id: u64) -> anyhow::Result<Client> {
+        let slot = self.slot(user_id);
+        let mut slot = slot.lock().await;
+
+        if let Some(client) = slot.client.clone() {
+            // Not retried, a failed check is how a broken connection shows and it's replaced
+            throttle().await;
+            match client.is_authorized().await {
+                Ok(_) => {
+                    slot.last_used = Some(Instant::now());
+                    return Ok(client);
+                }
+                Err(e) => info!("Client pool: connection of {} is broken, reconnecting: {}", user_id, e),
+            }
+        }
+
+        let client = self.connect(user_id).await?;
+        slot.client = Some(client.clone());
+        slot.last_used = Some(Instant::now());
+        Ok(client)
+    }
+
+    /// Writes the session of the client back to the user's file, e.g. after signing in.
+    pub(crate) fn save_session(&self, client: &Client, user_id: u64) -> anyhow::Result<()> {
+        self.sessions.save(client.session(), user_id)
+    }
+
+    /// Drops the client of the user without saving the session, for a user who signs out.
+    pub(crate) fn forget(&self, user_id: u64) {
+        self.slots().remove(&user_id);
+    }
+
+    /// Saves the sessions of all clients and disconnects them, called on shutdown.
+    pub(crate) async fn shutdown(&self) {
+        let slots: Vec<_> = self.slots().drain().collect();
+        for (user_id, slot) in slots {
+            if let Some(client) = slot.lock().await.client.take() {
+                if let Err(e) = self.save_session(&client, user_id) {
+                    info!("Client pool: failed to save the session of {}: {:?}", user_id, e);
+                }
+            }
+        }
+        info!("Client pool: all clients are closed");
+    }
+
+    async fn connect(&self, user_id: u64) -> anyhow::Result<Client> {
+        let client = Client::connect(Config {
+            session: self.sessions.load(user_id)?,
+            api_id: self.api_id,
+            api_hash: self.api_hash.clone(),
+            params: Default::default(),
+        })
+        .await?;
+        info!("Client pool: {} connected", user_id);
+
+        Ok(client)
+    }
+
+    async fn close_idle(&self) {
+        let slots: Vec<_> = self
+            .slots()
+            .iter()
+            .map(|(user_id, slot)| (*user_id, slot.clone()))
+            .collect();
+
+        for (user_id, slot) in slots {
+            // A slot that is locked is being used right now
+            let Ok(mut slot) = slot.try_lock() else {
+                continue;
+            };
+            if slot.last_used.is_some_and(|used| used.elapsed() < self.idle) {
+                continue;
+            }
+
+            if let Some(client) = slot.client.take() {
+                if let Err(e) = self.save_session(&client, user_id) {
+                    info!("Client pool: failed to save the session of {}: {:?}", user_id, e);
+                }
+                info!("Client pool: idle client of {} closed", user_id);
+            }
+        }
+    }
+
+    fn slot(&self, user_id: u64) -> Arc<tokio::sync::Mutex<Slot>> {
+        self.slots().entry(user_id).or_default().clone()
+    }
+
+    fn slots(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Arc<tokio::sync::Mutex<Slot>>>> {
+        self.slots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -1,23 +1,36 @@
-use crate::news_block_creation::news_block_creation;
-use grammers_client::{Client, Config};
-use grammers_session::Session;
+use crate::client_pool::ClientPool;
+use crate::llm::LlmProvider;
+use crate::news_block_creation::{news_block_creation, NewsOutcome};
+use crate::speech::SpeechSynthesizer;
+use crate::news_block_creation_utils::Lookback;
+use crate::pipeline::Progress;
+use crate::progress_message::ProgressMessage;
+use crate::storage::Repository;
+use anyhow::bail;
+use chrono_tz::Tz;
+use grammers_client::Client;
 use log::info;
 use serde_json::Value;
-use std::path::Path;
-use std::{env, fs};
+use std::env;
+use std::fs;
+use std::str::FromStr;
 use teloxide::payloads::{SendMessageSetters, SendVoiceSetters};
-use teloxide::prelude::{Message, Requester};
-use teloxide::types::{ChatAction, InputFile, ParseMode};
+use teloxide::prelude::{ChatId, Requester};
+use teloxide::types::{InputFile, ParseMode};
 use teloxide::Bot;
-use tokio::task;
-use tokio::task::JoinHandle;
-use tokio::time::sleep;
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn create_and_send_podcast(
     bot: Bot,
-    msg: Message,
+    chat_id: ChatId,
     client: &Client,
+    llm: &dyn LlmProvider,
+    speech: &dyn SpeechSynthesizer,
+    storage: &dyn Repository,
     language_code: &str,
+    time_zone: Tz,
+    lookback: Lookback,
+    progress: &dyn Progress,
 ) -> anyhow::Result<()> {
     let localization = load_localization(language_code);
 
@@ -29,76 +42,163 @@ pub(crate) async fn create_and_send_podcast(
         .as_str()
         .unwrap_or("Default message");
 
-    bot.send_message(msg.chat.id, start_message)
+    bot.send_message(chat_id, start_message)
         .parse_mode(ParseMode::Html)
         .await?;
 
-    let bot_clone = bot.clone();
-    let chat_id = msg.chat.id;
-    let recording_task: JoinHandle<()> = task::spawn(async move {
-        loop {
-            if let Err(_) = bot_clone
-                .send_chat_action(chat_id, ChatAction::RecordVoice)
-                .await
-            {
-                break;
-            }
-            sleep(std::time::Duration::from_secs(5)).await;
+    let progress = ProgressMessage::send(bot.clone(), chat_id, &localization, progress).await?;
+
+    let created = news_block_creation(
+        client,
+        llm,
+        speech,
+        storage,
+        chat_id.0 as u64,
+        time_zone,
+        lookback,
+        &progress,
+    )
+    .await;
+    let outcome = match created {
+        Ok(created) => {
+            progress.remove().await;
+            created
         }
-    });
-
-    let podcast_file = news_block_creation(client, msg.clone()).await?;
+        Err(e) => {
+            progress.fail().await;
+            return Err(e);
+        }
+    };
+
+    let news_block = match outcome {
+        NewsOutcome::Podcast(news_block) => news_block,
+        NewsOutcome::NothingNew {
+            since,
+            failed_sources,
+        } => {
+            let message = localization["create_and_send_podcast_fn"]["nothing_new"]
+                .as_str()
+                .unwrap_or("Default message")
+                .replace(
+                    "{since}",
+                    &since.with_timezone(&time_zone).format("%d.%m %H:%M").to_string(),
+                );
+            bot.send_message(chat_id, message).await?;
+            send_failed_sources(&bot, chat_id, &localization, &failed_sources).await?;
+            return Ok(());
+        }
+        NewsOutcome::AllSourcesFailed(failed_sources) => {
+            let message = localization["create_and_send_podcast_fn"]["all_sources_failed"]
+                .as_str()
+                .unwrap_or("Default message")
+                .replace("{sources}", &failed_sources.join(", "));
+            bot.send_message(chat_id, message).await?;
+            bail!("None of the {} sources could be read", failed_sources.len());
+        }
+    };
 
-    bot.send_message(msg.chat.id, end_message)
+    bot.send_message(chat_id, end_message)
         .parse_mode(ParseMode::Html)
         .await?;
 
-    bot.send_voice(msg.chat.id, InputFile::file(podcast_file.clone()))
+    let podcast = &news_block.audio;
+    let sent = bot
+        .send_voice(chat_id, InputFile::file(podcast.path.clone()))
+        .duration(podcast.duration.as_secs() as u32)
         .parse_mode(ParseMode::Html)
-        .await?;
+        .await;
+
+    // The file goes away even when sending failed, nothing picks it up later
+    fs::remove_file(&podcast.path)?;
+    info!("Podcast file: {:?} has been removed", podcast.path);
+    sent?;
 
-    fs::remove_file(podcast_file.clone())?;
-    info!("Podcast file: {:?} has been sent and removed", podcast_file);
+    // Only a delivered podcast marks its updates as seen, the next one retells a lost podcast
+    for (source_id, message_id) in &news_block.newest_seen {
+        storage.save_last_seen(chat_id.0 as u64, *source_id, *message_id)?;
+    }
 
-    recording_task.abort();
+    send_failed_sources(&bot, chat_id, &localization, &news_block.failed_sources).await
+}
 
+async fn send_failed_sources(
+    bot: &Bot,
+    chat_id: ChatId,
+    localization: &Value,
+    failed_sources: &[String],
+) -> anyhow::Result<()> {
+    if !failed_sources.is_empty() {
+        let failed_message = localization["create_and_send_podcast_fn"]["failed_sources"]
+            .as_str()
+            .unwrap_or("Default message")
+            .replace("{sources}", &failed_sources.join(", "));
+        bot.send_message(chat_id, failed_message).await?;
+    }
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn handle_getnews_cmd(
     bot: Bot,
-    msg: Message,
+    chat_id: ChatId,
+    clients: &ClientPool,
+    llm: &dyn LlmProvider,
+    speech: &dyn SpeechSynthesizer,
+    storage: &dyn Repository,
     language_code: &str,
+    time_zone: Tz,
+    lookback: Lookback,
+    progress: &dyn Progress,
 ) -> anyhow::Result<()> {
-    let api_id: i32 = env::var("TELEGRAM_API_ID")
-        .expect("API_ID not set")
-        .parse()
-        .expect("API_ID must be a number");
-    let api_hash = env::var("TELEGRAM_API_HASH").expect("API_HASH not set");
-    let user_id = msg.chat.id;
-    let user_sessions_dir = Path::new("users_sessions");
-    if !user_sessions_dir.exists() {
-        fs::create_dir(user_sessions_dir).expect("Failed to create 'users_sessions' folder");
-    }
-
-    let session_file = format!("users_sessions/{}.session", user_id);
-    let session_path = Path::new(&session_file);
-
-    let client = Client::connect(Config {
-        session: Session::load_file_or_create(session_path)?,
-        api_id,
-        api_hash,
-        params: Default::default(),
-    })
+    let client = clients.get(chat_id.0 as u64).await?;
+
+    create_and_send_podcast(
+        bot,
+        chat_id,
+        &client,
+        llm,
+        speech,
+        storage,
+        language_code,
+        time_zone,
+        lookback,
+        progress,
+    )
     .await?;
 
-    create_and_send_podcast(bot, msg, &client, &language_code).await?;
-
     Ok(())
 }
 
+/// A setting from the env var `name`, at least 1; `default` when it's not set or not a number.
+pub(crate) fn positive_from_env<T: FromStr + Ord + From<u8>>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+        .max(T::from(1))
+}
+
 pub(crate) fn load_localization(language_code: &str) -> Value {
     let file_path = format!("localization/{}.json", language_code);
     let data = fs::read_to_string(file_path).expect("Unable to read localization file");
     serde_json::from_str(&data).expect("Unable to parse localization JSON")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positive_from_env_falls_back_and_clamps() {
+        assert_eq!(positive_from_env("NEWS_WIZARD_TEST_UNSET", 4usize), 4);
+
+        env::set_var("NEWS_WIZARD_TEST_GARBAGE", "many");
+        assert_eq!(positive_from_env("NEWS_WIZARD_TEST_GARBAGE", 4usize), 4);
+
+        env::set_var("NEWS_WIZARD_TEST_ZERO", "0");
+        assert_eq!(positive_from_env("NEWS_WIZARD_TEST_ZERO", 4u32), 1);
+
+        env::set_var("NEWS_WIZARD_TEST_SET", "72");
+        assert_eq!(positive_from_env("NEWS_WIZARD_TEST_SET", 9i64), 72);
+    }
+}
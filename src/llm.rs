@@ -0,0 +1,644 @@
+use crate::auth::LoginCode;
+use crate::common_utils::positive_from_env;
+use crate::news_block_creation_utils::SourceSummary;
+use crate::relevance::{BatchVerdicts, SingleVerdict};
+use anyhow::{bail, Context, Result};
+use async_openai::config::OpenAIConfig;
+use async_openai::types::{
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+    CreateChatCompletionRequestArgs, ResponseFormat, ResponseFormatJsonSchema,
+};
+use async_openai::Client as LLM_Client;
+use async_trait::async_trait;
+use log::info;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::env;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+const DEFAULT_OPENAI_MODEL: &str = "gpt-4o-2024-08-06";
+const DEFAULT_OLLAMA_MODEL: &str = "llama3.1";
+const DEFAULT_OLLAMA_URL: &str = "http://localhost:11434";
+const MAX_TOKENS: u32 = 8192;
+const TEMPERATURE: f32 = 0.4;
+const NO_ANSWER: &str = "Извини, я не смог понять твой вопрос. Пожалуйста, попробуй снова.";
+const MAX_STRUCTURED_ATTEMPTS: usize = 3;
+const DEFAULT_LLM_CONCURRENCY: usize = 8;
+
+/// A chat model the bot talks to: every prompt is a system role plus a single user request.
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    async fn complete(&self, system_role: &str, request: &str) -> Result<String>;
+
+    /// Same as `complete`, but the answer is a JSON object constrained by `schema`.
+    async fn complete_json(
+        &self,
+        system_role: &str,
+        request: &str,
+        name: &str,
+        schema: &Value,
+    ) -> Result<String>;
+}
+
+/// A typed answer of the model, requested with a JSON schema.
+pub(crate) trait StructuredOutput: DeserializeOwned {
+    /// Name of the schema, latin letters, digits, `_` and `-` only.
+    const NAME: &'static str;
+
+    /// The schema in the strict form: every property is required, no additional ones.
+    fn schema() -> Value;
+
+    /// Checks what the schema can't express.
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// What the mock provider answers to `request`, it has to pass `validate`.
+    fn mock_example(_request: &str) -> Value {
+        example_for_schema(&Self::schema())
+    }
+}
+
+/// Asks for a structured answer and parses it, a malformed or invalid answer is asked for again
+/// with the reason, up to `MAX_STRUCTURED_ATTEMPTS` times.
+pub(crate) async fn complete_structured<T: StructuredOutput>(
+    llm: &dyn LlmProvider,
+    system_role: &str,
+    request: &str,
+) -> Result<T> {
+    let schema = T::schema();
+    let mut prompt = request.to_string();
+
+    for attempt in 1..=MAX_STRUCTURED_ATTEMPTS {
+        let response = llm
+            .complete_json(system_role, &prompt, T::NAME, &schema)
+            .await?;
+
+        let error = match parse_json::<T>(&response) {
+            Ok(output) => match output.validate() {
+                Ok(()) => return Ok(output),
+                Err(e) => e,
+            },
+            Err(e) => e.to_string(),
+        };
+
+        info!(
+            "Malformed '{}' answer (attempt {}): {}",
+            T::NAME,
+            attempt,
+            error
+        );
+        prompt = format!(
+            "{}\n\nТвой предыдущий ответ не подошёл: {}. Ответь строго JSON-объектом по заданной схеме.",
+            request, error
+        );
+    }
+
+    bail!(
+        "No valid '{}' answer after {} attempts",
+        T::NAME,
+        MAX_STRUCTURED_ATTEMPTS
+    )
+}
+
+/// Some servers wrap the JSON into a code block even in the JSON mode.
+fn parse_json<T: DeserializeOwned>(response: &str) -> serde_json::Result<T> {
+    let start = response.find('{').unwrap_or(0);
+    let end = response.rfind('}').map_or(response.len(), |end| end + 1);
+    serde_json::from_str(response.get(start..end).unwrap_or(response))
+}
+
+/// OpenAI itself or any server speaking its API (vLLM, llama.cpp server and so on).
+pub(crate) struct OpenAiProvider {
+    client: LLM_Client<OpenAIConfig>,
+    model: String,
+}
+
+impl OpenAiProvider {
+    /// Uses the `OPENAI_API_KEY` env var.
+    pub(crate) fn new(model: String) -> Self {
+        Self {
+            client: LLM_Client::new(),
+            model,
+        }
+    }
+
+    pub(crate) fn compatible(base_url: String, api_key: String, model: String) -> Self {
+        let config = OpenAIConfig::new()
+            .with_api_base(base_url)
+            .with_api_key(api_key);
+        Self {
+            client: LLM_Client::with_config(config),
+            model,
+        }
+    }
+
+    async fn chat(
+        &self,
+        system_role: &str,
+        request: &str,
+        response_format: Option<ResponseFormat>,
+    ) -> Result<String> {
+        let mut llm_request = CreateChatCompletionRequestArgs::default();
+        if let Some(response_format) = response_format {
+            llm_request.response_format(response_format);
+        }
+
+        let llm_request = llm_request
+            .max_tokens(MAX_TOKENS)
+            .model(self.model.as_str())
+            .temperature(TEMPERATURE)
+            .messages([
+                ChatCompletionRequestSystemMessageArgs::default()
+                    .content(system_role)
+                    .build()?
+                    .into(),
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(request)
+                    .build()?
+                    .into(),
+            ])
+            .build()?;
+
+        let response = self.client.chat().create(llm_request).await?;
+
+        Ok(response
+            .choices
+            .first()
+            .and_then(|choice| choice.message.content.clone())
+            .unwrap_or_else(|| NO_ANSWER.to_string()))
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OpenAiProvider {
+    async fn complete(&self, system_role: &str, request: &str) -> Result<String> {
+        self.chat(system_role, request, None).await
+    }
+
+    async fn complete_json(
+        &self,
+        system_role: &str,
+        request: &str,
+        name: &str,
+        schema: &Value,
+    ) -> Result<String> {
+        let response_format = ResponseFormat::JsonSchema {
+            json_schema: ResponseFormatJsonSchema {
+                description: None,
+                name: name.to_string(),
+                schema: Some(schema.clone()),
+                strict: Some(true),
+            },
+        };
+        self.chat(system_role, request, Some(response_format)).await
+    }
+}
+
+/// A model served by a local Ollama instance through its native chat API.
+pub(crate) struct OllamaProvider {
+    http: reqwest::Client,
+    base_url: String,
+    model: String,
+}
+
+impl OllamaProvider {
+    pub(crate) fn new(base_url: String, model: String) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model,
+        }
+    }
+
+    async fn chat(&self, system_role: &str, request: &str, format: Option<&Value>) -> Result<String> {
+        let mut body = json!({
+            "model": self.model,
+            "stream": false,
+            "options": { "temperature": TEMPERATURE, "num_predict": MAX_TOKENS },
+            "messages": [
+                { "role": "system", "content": system_role },
+                { "role": "user", "content": request },
+            ],
+        });
+        if let Some(format) = format {
+            body["format"] = format.clone();
+        }
+
+        let response: Value = self
+            .http
+            .post(format!("{}/api/chat", self.base_url))
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(response["message"]["content"]
+            .as_str()
+            .unwrap_or(NO_ANSWER)
+            .to_string())
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OllamaProvider {
+    async fn complete(&self, system_role: &str, request: &str) -> Result<String> {
+        self.chat(system_role, request, None).await
+    }
+
+    async fn complete_json(
+        &self,
+        system_role: &str,
+        request: &str,
+        _name: &str,
+        schema: &Value,
+    ) -> Result<String> {
+        self.chat(system_role, request, Some(schema)).await
+    }
+}
+
+/// Answers without any model, so the whole pipeline can run offline and gives the same result
+/// every time. Plain answers are the configured response if there is one, or the request itself;
+/// JSON answers are valid objects of the schema the bot asked for.
+pub(crate) struct MockProvider {
+    response: Option<String>,
+}
+
+impl MockProvider {
+    pub(crate) fn new(response: Option<String>) -> Self {
+        Self { response }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for MockProvider {
+    async fn complete(&self, _system_role: &str, request: &str) -> Result<String> {
+        Ok(self.response.clone().unwrap_or_else(|| request.to_string()))
+    }
+
+    async fn complete_json(
+        &self,
+        _system_role: &str,
+        request: &str,
+        name: &str,
+        schema: &Value,
+    ) -> Result<String> {
+        Ok(mock_answer(request, name, schema).to_string())
+    }
+}
+
+/// The canned answer of the type the schema belongs to, the simplest value of the schema when
+/// the type isn't known.
+fn mock_answer(request: &str, name: &str, schema: &Value) -> Value {
+    type MockExample = fn(&str) -> Value;
+    let known: [(&str, MockExample); 4] = [
+        (SourceSummary::NAME, SourceSummary::mock_example),
+        (BatchVerdicts::NAME, BatchVerdicts::mock_example),
+        (SingleVerdict::NAME, SingleVerdict::mock_example),
+        (LoginCode::NAME, LoginCode::mock_example),
+    ];
+    known
+        .iter()
+        .find(|(known_name, _)| *known_name == name)
+        .map_or_else(|| example_for_schema(schema), |(_, example)| example(request))
+}
+
+/// The first enum variant, empty strings and arrays, zeros and so on.
+fn example_for_schema(schema: &Value) -> Value {
+    if let Some(variant) = schema["enum"].as_array().and_then(|variants| variants.first()) {
+        return variant.clone();
+    }
+
+    match schema["type"].as_str() {
+        Some("object") => Value::Object(
+            schema["properties"]
+                .as_object()
+                .map(|properties| {
+                    properties
+                        .iter()
+                        .map(|(name, property)| (name.clone(), example_for_schema(property)))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        ),
+        Some("array") => json!([]),
+        Some("string") => json!(""),
+        Some("integer") | Some("number") => json!(0),
+        Some("boolean") => json!(false),
+        _ => Value::Null,
+    }
+}
+
+/// Lets at most a fixed number of requests reach the wrapped provider at once, for every user
+/// together, the others wait for a free slot.
+pub(crate) struct LimitedLlm {
+    inner: Arc<dyn LlmProvider>,
+    permits: Semaphore,
+}
+
+impl LimitedLlm {
+    pub(crate) fn new(inner: Arc<dyn LlmProvider>, limit: usize) -> Self {
+        Self {
+            inner,
+            permits: Semaphore::new(limit.max(1)),
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for LimitedLlm {
+    async fn complete(&self, system_role: &str, request: &str) -> Result<String> {
+        let _permit = self.permits.acquire().await?;
+        self.inner.complete(system_role, request).await
+    }
+
+    async fn complete_json(
+        &self,
+        system_role: &str,
+        request: &str,
+        name: &str,
+        schema: &Value,
+    ) -> Result<String> {
+        let _permit = self.permits.acquire().await?;
+        self.inner
+            .complete_json(system_role, request, name, schema)
+            .await
+    }
+}
+
+/// Picks the provider with the `LLM_PROVIDER` env var: `openai` (default), `openai-compatible`,
+/// `ollama` or `mock`. `LLM_MODEL`, `LLM_BASE_URL` and `LLM_API_KEY` tune the chosen one,
+/// `LLM_CONCURRENCY` caps the requests in flight.
+pub(crate) fn provider_from_env() -> Result<Arc<dyn LlmProvider>> {
+    let kind = env::var("LLM_PROVIDER").unwrap_or_else(|_| "openai".to_string());
+    let model = env::var("LLM_MODEL").ok();
+    let base_url = env::var("LLM_BASE_URL").ok();
+
+    let provider: Arc<dyn LlmProvider> = match kind.as_str() {
+        "openai" => Arc::new(OpenAiProvider::new(
+            model.unwrap_or_else(|| DEFAULT_OPENAI_MODEL.to_string()),
+        )),
+        "openai-compatible" => Arc::new(OpenAiProvider::compatible(
+            base_url.context("LLM_BASE_URL must be set for the openai-compatible provider")?,
+            env::var("LLM_API_KEY").unwrap_or_default(),
+            model.context("LLM_MODEL must be set for the openai-compatible provider")?,
+        )),
+        "ollama" => Arc::new(OllamaProvider::new(
+            base_url.unwrap_or_else(|| DEFAULT_OLLAMA_URL.to_string()),
+            model.unwrap_or_else(|| DEFAULT_OLLAMA_MODEL.to_string()),
+        )),
+        "mock" => Arc::new(MockProvider::new(env::var("LLM_MOCK_RESPONSE").ok())),
+        other => bail!("Unknown LLM_PROVIDER: {}", other),
+    };
+
+    let concurrency = positive_from_env("LLM_CONCURRENCY", DEFAULT_LLM_CONCURRENCY);
+
+    info!("LLM provider: {}, {} requests at once", kind, concurrency);
+    Ok(Arc::new(LimitedLlm::new(provider, concurrency)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    #[derive(Debug, Deserialize)]
+    struct Headline {
+        title: String,
+        tag: String,
+    }
+
+    impl StructuredOutput for Headline {
+        const NAME: &'static str = "headline";
+
+        fn schema() -> Value {
+            json!({
+                "type": "object",
+                "properties": {
+                    "title": { "type": "string" },
+                    "tag": { "type": "string", "enum": ["world", "local"] },
+                },
+                "required": ["title", "tag"],
+                "additionalProperties": false,
+            })
+        }
+
+        fn validate(&self) -> Result<(), String> {
+            if self.title.is_empty() {
+                return Err("\"title\" is empty".to_string());
+            }
+            Ok(())
+        }
+    }
+
+    /// Lets the tag alone through, so the example answer of the mock is a valid one.
+    #[derive(Debug, Deserialize)]
+    struct Tag {
+        tag: String,
+    }
+
+    impl StructuredOutput for Tag {
+        const NAME: &'static str = "tag";
+
+        fn schema() -> Value {
+            json!({
+                "type": "object",
+                "properties": { "tag": { "type": "string", "enum": ["world", "local"] } },
+                "required": ["tag"],
+                "additionalProperties": false,
+            })
+        }
+    }
+
+    /// Counts the requests that reach the wrapped provider.
+    struct Counted<P> {
+        inner: P,
+        requests: AtomicUsize,
+    }
+
+    impl<P> Counted<P> {
+        fn new(inner: P) -> Self {
+            Self {
+                inner,
+                requests: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl<P: LlmProvider> LlmProvider for Counted<P> {
+        async fn complete(&self, system_role: &str, request: &str) -> Result<String> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            self.inner.complete(system_role, request).await
+        }
+
+        async fn complete_json(
+            &self,
+            system_role: &str,
+            request: &str,
+            name: &str,
+            schema: &Value,
+        ) -> Result<String> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            self.inner
+                .complete_json(system_role, request, name, schema)
+                .await
+        }
+    }
+
+    /// Gives the prepared answers one after another and remembers the requests.
+    struct Scripted {
+        answers: Mutex<VecDeque<&'static str>>,
+        requests: Mutex<Vec<String>>,
+    }
+
+    impl Scripted {
+        fn new(answers: &[&'static str]) -> Self {
+            Self {
+                answers: Mutex::new(answers.iter().copied().collect()),
+                requests: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl LlmProvider for Scripted {
+        async fn complete(&self, system_role: &str, request: &str) -> Result<String> {
+            self.complete_json(system_role, request, "", &Value::Null)
+                .await
+        }
+
+        async fn complete_json(
+            &self,
+            _system_role: &str,
+            request: &str,
+            _name: &str,
+            _schema: &Value,
+        ) -> Result<String> {
+            self.requests.lock().unwrap().push(request.to_string());
+            let answer = self.answers.lock().unwrap().pop_front();
+            Ok(answer.context("No answers left")?.to_string())
+        }
+    }
+
+    #[test]
+    fn parse_json_reads_an_object_wrapped_in_a_code_block() {
+        let response = "Here you go:\n```json\n{\"title\": \"Rain\", \"tag\": \"local\"}\n```";
+
+        let headline: Headline = parse_json(response).unwrap();
+
+        assert_eq!(headline.title, "Rain");
+        assert_eq!(headline.tag, "local");
+    }
+
+    #[test]
+    fn parse_json_rejects_an_answer_without_an_object() {
+        assert!(parse_json::<Headline>("I can't answer that").is_err());
+        assert!(parse_json::<Headline>("{\"title\": \"Rain\"}").is_err());
+    }
+
+    #[test]
+    fn example_for_schema_takes_the_simplest_values() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "title": { "type": "string" },
+                "tag": { "type": "string", "enum": ["world", "local"] },
+                "items": { "type": "array", "items": { "type": "string" } },
+                "count": { "type": "integer" },
+                "urgent": { "type": "boolean" },
+                "source": {
+                    "type": "object",
+                    "properties": { "id": { "type": "number" } },
+                },
+            },
+        });
+
+        assert_eq!(
+            example_for_schema(&schema),
+            json!({
+                "title": "",
+                "tag": "world",
+                "items": [],
+                "count": 0,
+                "urgent": false,
+                "source": { "id": 0 },
+            })
+        );
+    }
+
+    /// The mock answers `request` with a valid object at once.
+    async fn assert_mock_answers<T: StructuredOutput>(request: &str) -> T {
+        let llm = Counted::new(MockProvider::new(None));
+        let answer = complete_structured::<T>(&llm, "role", request).await;
+        assert_eq!(llm.requests.load(Ordering::SeqCst), 1, "{}", T::NAME);
+        answer.unwrap()
+    }
+
+    #[tokio::test]
+    async fn mock_answers_every_known_schema_validly() {
+        let summary: SourceSummary =
+            assert_mock_answers("Источник: Новости\nНачало обновления:\nДождь\n").await;
+        assert_eq!(summary.source, "Новости");
+        assert_mock_answers::<SourceSummary>("").await;
+        assert_mock_answers::<BatchVerdicts>(r#"[{"id": 1, "text": "Дождь"}]"#).await;
+        assert_mock_answers::<SingleVerdict>("Дождь").await;
+        assert_mock_answers::<LoginCode>("не помню").await;
+    }
+
+    #[test]
+    fn mock_answer_takes_the_login_code_the_user_typed() {
+        assert_eq!(
+            mock_answer("Код: 4 8 1 5 2", LoginCode::NAME, &Value::Null),
+            json!({ "code": "48152" })
+        );
+    }
+
+    #[tokio::test]
+    async fn complete_structured_accepts_a_valid_answer_at_once() {
+        let llm = Counted::new(MockProvider::new(None));
+
+        let tag: Tag = complete_structured(&llm, "role", "request").await.unwrap();
+
+        assert_eq!(tag.tag, "world");
+        assert_eq!(llm.requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn complete_structured_gives_up_on_invalid_answers() {
+        // The mock's example has an empty title, which never passes validation
+        let llm = Counted::new(MockProvider::new(None));
+
+        let answer = complete_structured::<Headline>(&llm, "role", "request").await;
+
+        assert!(answer.is_err());
+        assert_eq!(
+            llm.requests.load(Ordering::SeqCst),
+            MAX_STRUCTURED_ATTEMPTS
+        );
+    }
+
+    #[tokio::test]
+    async fn complete_structured_asks_again_with_the_reason() {
+        let llm = Scripted::new(&[
+            "not json at all",
+            "{\"title\": \"\", \"tag\": \"world\"}",
+            "{\"title\": \"Rain\", \"tag\": \"local\"}",
+        ]);
+
+        let headline: Headline = complete_structured(&llm, "role", "request").await.unwrap();
+
+        assert_eq!(headline.title, "Rain");
+        let requests = llm.requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0], "request");
+        assert!(requests[1].starts_with("request\n\n"));
+        assert!(requests[2].contains("\"title\" is empty"));
+    }
+}
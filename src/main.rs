@@ -1,27 +1,53 @@
 mod ai_utils;
 mod auth;
+mod client_pool;
 mod common_utils;
+mod llm;
 mod news_block_creation;
 mod news_block_creation_utils;
+mod pipeline;
+mod progress_message;
+mod queue;
+mod relevance;
 mod scheduled_task;
+mod session_store;
+mod sources;
+mod speech;
+mod storage;
+mod telegram;
+mod tokens;
 
 use anyhow::Result;
+use chrono::Utc;
+use chrono_tz::Tz;
 use dotenv::dotenv;
 use grammers_client::types::{LoginToken, PasswordToken};
 use grammers_client::Client;
 use log::info;
 use std::collections::HashMap;
+use std::path::Path;
 use std::sync::Arc;
 use std::{env, fs};
 use teloxide::macros::BotCommands;
 use teloxide::prelude::*;
 use teloxide::types::{ParseMode, UpdateKind};
+use teloxide::utils::html;
 use tokio::sync::Mutex;
 // use tracing_appender::rolling::{RollingFileAppender, Rotation};
-use crate::auth::{authentication, session_file_creation};
-use crate::common_utils::handle_getnews_cmd;
-// use crate::common_utils::load_localization;
-use crate::scheduled_task::schedule_daily_getnews_task;
+use crate::auth::{authentication, restore_interrupted_login, session_file_creation, sign_out};
+use crate::client_pool::ClientPool;
+use crate::common_utils::load_localization;
+use crate::llm::{provider_from_env, LlmProvider};
+use crate::news_block_creation_utils::DEFAULT_LOOKBACK;
+use crate::queue::{Enqueued, JobStatus, PodcastQueue};
+use crate::scheduled_task::{
+    format_rules, parse_schedule_args, DeliveryRule, DeliverySchedule, Scheduler, SystemClock,
+    DEFAULT_TIME_ZONE,
+};
+use crate::sources::{apply_sources_callback, handle_sources_cmd, SourcesCallback};
+use crate::session_store::SessionStore;
+use crate::speech::speech_from_env;
+use crate::storage::{Repository, SqliteRepository};
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
 #[tokio::main]
@@ -43,9 +69,48 @@ async fn main() -> Result<()> {
 
     info!("Starting News Wizard...");
 
+    let sessions_dir = env::var("SESSIONS_DIR").unwrap_or_else(|_| "users_sessions".to_string());
+    let sessions = SessionStore::from_env("SESSION_ENCRYPTION_KEY", &sessions_dir)?;
+    sessions.encrypt_plain_files()?;
+
+    // `news_wizard rotate-session-key` re-encrypts the session files from OLD_SESSION_ENCRYPTION_KEY
+    // to SESSION_ENCRYPTION_KEY and exits, run it while the bot is stopped
+    if env::args().nth(1).as_deref() == Some("rotate-session-key") {
+        let old = SessionStore::from_env("OLD_SESSION_ENCRYPTION_KEY", &sessions_dir)?;
+        let rotated = sessions.rotate_from(&old)?;
+        info!("Session files moved to the new key: {}", rotated);
+        return Ok(());
+    }
+
     let bot = Bot::from_env();
 
-    let app_state = Arc::new(AppState::default());
+    let database_path =
+        env::var("DATABASE_PATH").unwrap_or_else(|_| "data/news_wizard.db".to_string());
+    let storage = Arc::new(SqliteRepository::open(&database_path)?);
+    info!("Storage opened at {}", database_path);
+
+    let app_state = Arc::new(AppState::new(storage.clone())?);
+
+    let llm = provider_from_env()?;
+    let speech = speech_from_env()?;
+
+    let clients = ClientPool::from_env(sessions);
+
+    let queue = Arc::new(PodcastQueue::new(
+        bot.clone(),
+        clients.clone(),
+        storage.clone(),
+        llm.clone(),
+        speech,
+    ));
+    queue.restore().await?;
+
+    let scheduler = Arc::new(Scheduler::new(
+        storage,
+        Arc::new(queue.clone()),
+        Arc::new(SystemClock),
+    ));
+    scheduler.restore()?;
 
     let cmd_handler = Update::filter_message()
         .filter_command::<NewsWizardCommands>()
@@ -53,10 +118,15 @@ async fn main() -> Result<()> {
 
     let chat_handler = Update::filter_message().endpoint(message_handler);
 
-    let handler = dptree::entry().branch(cmd_handler).branch(chat_handler);
+    let callback_handler = Update::filter_callback_query().endpoint(callback_handler);
+
+    let handler = dptree::entry()
+        .branch(cmd_handler)
+        .branch(chat_handler)
+        .branch(callback_handler);
 
     Dispatcher::builder(bot.clone(), handler)
-        .dependencies(dptree::deps![app_state])
+        .dependencies(dptree::deps![app_state, scheduler, queue, clients.clone(), llm])
         .enable_ctrlc_handler()
         .build()
         .dispatch_with_listener(
@@ -67,6 +137,8 @@ async fn main() -> Result<()> {
         )
         .await;
 
+    clients.shutdown().await;
+
     Ok(())
 }
 
@@ -78,6 +150,7 @@ pub struct AuthStages {
     pub phone_number: Option<String>,
     pub passcode: Option<String>,
     pub two_fa: Option<String>,
+    /// The client of a login in progress, the pool hands it out otherwise.
     pub client: Option<Client>,
     pub token: Option<Arc<LoginToken>>,
     pub password_token: Option<PasswordToken>,
@@ -86,12 +159,82 @@ pub struct AuthStages {
 #[derive(Default, Clone)]
 pub struct UserData {
     pub language_code: Option<String>,
+    pub time_zone: Option<Tz>,
+    pub delivery_rules: Vec<DeliveryRule>,
+}
+
+impl UserData {
+    pub(crate) fn time_zone(&self) -> Tz {
+        self.time_zone.unwrap_or(DEFAULT_TIME_ZONE)
+    }
+
+    pub(crate) fn delivery_rules(&self) -> Vec<DeliveryRule> {
+        if self.delivery_rules.is_empty() {
+            vec![DeliveryRule::default()]
+        } else {
+            self.delivery_rules.clone()
+        }
+    }
 }
 
 #[derive(Default)]
+pub struct UserSession {
+    pub state: AuthStages,
+    pub data: UserData,
+}
+
 pub struct AppState {
-    pub user_state: Mutex<HashMap<u64, AuthStages>>,
-    pub user_data: Mutex<HashMap<u64, UserData>>,
+    /// Every user has a lock of their own, so a slow command of one user doesn't hold up the
+    /// others; the map itself is locked only to look a session up.
+    sessions: std::sync::Mutex<HashMap<u64, Arc<Mutex<UserSession>>>>,
+    pub storage: Arc<dyn Repository>,
+}
+
+impl AppState {
+    pub(crate) fn new(storage: Arc<dyn Repository>) -> Result<Self> {
+        let user_state = storage.load_auth_stages()?;
+        let user_data = storage.load_user_data()?;
+        info!(
+            "Loaded {} users and {} auth states from storage",
+            user_data.len(),
+            user_state.len()
+        );
+
+        let mut sessions: HashMap<u64, UserSession> = HashMap::new();
+        for (user_id, data) in user_data {
+            sessions.entry(user_id).or_default().data = data;
+        }
+        for (user_id, state) in user_state {
+            sessions.entry(user_id).or_default().state = state;
+        }
+
+        Ok(Self {
+            sessions: std::sync::Mutex::new(
+                sessions
+                    .into_iter()
+                    .map(|(user_id, session)| (user_id, Arc::new(Mutex::new(session))))
+                    .collect(),
+            ),
+            storage,
+        })
+    }
+
+    /// The session of the user, a new one is started for a user the bot doesn't know yet.
+    pub(crate) fn session(&self, user_id: u64) -> Arc<Mutex<UserSession>> {
+        self.sessions().entry(user_id).or_default().clone()
+    }
+
+    pub(crate) fn existing_session(&self, user_id: u64) -> Option<Arc<Mutex<UserSession>>> {
+        self.sessions().get(&user_id).cloned()
+    }
+
+    pub(crate) fn forget(&self, user_id: u64) {
+        self.sessions().remove(&user_id);
+    }
+
+    fn sessions(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Arc<Mutex<UserSession>>>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
 }
 
 #[derive(BotCommands, Clone)]
@@ -101,7 +244,14 @@ enum NewsWizardCommands {
     GetNews,
     Help,
     Auth,
-    // SignOut,
+    Schedule(String),
+    Pause(String),
+    Resume,
+    Stop,
+    Sources,
+    Status,
+    Cancel,
+    SignOut,
 }
 
 async fn command_handler(
@@ -109,16 +259,19 @@ async fn command_handler(
     msg: Message,
     cmd: NewsWizardCommands,
     app_state: Arc<AppState>,
+    scheduler: Arc<Scheduler>,
+    queue: Arc<PodcastQueue>,
+    clients: Arc<ClientPool>,
 ) -> Result<()> {
     let user_id = msg.from.as_ref().map(|user| user.id.0).unwrap_or(0);
     let username = msg.chat.username().unwrap_or("Unknown User");
-    let mut user_state = app_state.user_state.lock().await;
-    let mut user_data = app_state.user_data.lock().await;
-    let state = user_state.entry(user_id).or_insert(AuthStages::default());
-    let data = user_data.entry(user_id).or_insert(UserData::default());
+    let session = app_state.session(user_id);
+    let mut session = session.lock().await;
+    let UserSession { state, data } = &mut *session;
 
     if data.language_code.is_none() {
         data.language_code = msg.clone().from.and_then(|user| user.language_code.clone());
+        app_state.storage.save_user_data(user_id, data)?;
     }
 
     let language_code = match data.language_code.as_deref() {
@@ -126,13 +279,7 @@ async fn command_handler(
         _ => "en".to_string(),
     };
 
-    // let localization = load_localization(&language_code);
-
-    let api_id: i32 = env::var("TELEGRAM_API_ID")
-        .expect("API_ID not set")
-        .parse()
-        .expect("API_ID must be a number");
-    let api_hash = env::var("TELEGRAM_API_HASH").expect("API_HASH not set");
+    let localization = load_localization(&language_code);
 
     match cmd {
         NewsWizardCommands::Start => {
@@ -155,56 +302,310 @@ async fn command_handler(
         }
         NewsWizardCommands::Auth => {
             info!("Auth cmd used by {}: Starting authentication...", username);
-            authentication(
+            let auth_result = authentication(
                 bot.clone(),
                 msg.clone(),
                 state,
                 user_id,
-                api_id,
-                api_hash.clone(),
+                &clients,
+                &language_code,
+            )
+            .await;
+            app_state.storage.save_auth_stages(user_id, state)?;
+            auth_result?;
+        }
+        NewsWizardCommands::SignOut => {
+            info!("Signout cmd used by {}: Forgetting the user...", username);
+
+            let signed_out = sign_out(state, user_id, &clients).await?;
+            scheduler.stop(msg.chat.id)?;
+            queue.cancel(msg.chat.id)?;
+
+            let user_tmp_dir = format!("tmp/{}", user_id);
+            if Path::new(&user_tmp_dir).exists() {
+                fs::remove_dir_all(&user_tmp_dir)?;
+                info!("Signout cmd: {} removed", user_tmp_dir);
+            }
+
+            app_state.storage.delete_user(user_id)?;
+            app_state.forget(user_id);
+
+            let mut message = if signed_out {
+                localization["signout_cmd"]["session_ended"].as_str()
+            } else {
+                localization["signout_cmd"]["not_authorized"].as_str()
+            }
+            .unwrap_or("Default message")
+            .to_string();
+            message.push_str(
+                localization["signout_cmd"]["data_deleted"]
+                    .as_str()
+                    .unwrap_or("Default message"),
+            );
+
+            bot.send_message(msg.chat.id, message).await?;
+        }
+        NewsWizardCommands::Schedule(args) => {
+            info!("Schedule cmd used by {}: {}", username, args);
+
+            let active_job = scheduler.active_job(msg.chat.id);
+
+            if !args.trim().is_empty() {
+                match parse_schedule_args(&args) {
+                    Ok((rules, time_zone)) => {
+                        if !rules.is_empty() {
+                            data.delivery_rules = rules;
+                        }
+                        if time_zone.is_some() {
+                            data.time_zone = time_zone;
+                        }
+                        app_state.storage.save_user_data(user_id, data)?;
+
+                        // Only re-arm users who already get podcasts, /getnews subscribes the rest
+                        if let Some(job) = active_job.as_ref() {
+                            scheduler.schedule(DeliverySchedule {
+                                chat_id: msg.chat.id,
+                                language_code: language_code.clone(),
+                                rules: data.delivery_rules(),
+                                time_zone: data.time_zone(),
+                                paused_until: job.paused_until,
+                            })?;
+                        }
+                    }
+                    Err(token) => {
+                        let message = localization["schedule_cmd"]["invalid"]
+                            .as_str()
+                            .unwrap_or("Default message")
+                            .replace("{}", &html::escape(&token));
+                        bot.send_message(msg.chat.id, message)
+                            .parse_mode(ParseMode::Html)
+                            .await?;
+                        return Ok(());
+                    }
+                }
+            }
+
+            let time_zone = data.time_zone();
+            let mut message = localization["schedule_cmd"]["current"]
+                .as_str()
+                .unwrap_or("Default message")
+                .replace("{rules}", &format_rules(&data.delivery_rules()))
+                .replace("{time_zone}", time_zone.name());
+
+            if let Some(job) = scheduler.active_job(msg.chat.id) {
+                if let Some(paused_until) = job.paused_until.filter(|until| *until > Utc::now()) {
+                    message.push_str(
+                        &localization["schedule_cmd"]["paused"]
+                            .as_str()
+                            .unwrap_or("Default message")
+                            .replace(
+                                "{}",
+                                &paused_until
+                                    .with_timezone(&time_zone)
+                                    .format("%d.%m %H:%M")
+                                    .to_string(),
+                            ),
+                    );
+                }
+                if let Some((next_run, _)) = job.next_run(Utc::now()) {
+                    message.push_str(
+                        &localization["schedule_cmd"]["next_run"]
+                            .as_str()
+                            .unwrap_or("Default message")
+                            .replace(
+                                "{}",
+                                &next_run
+                                    .with_timezone(&time_zone)
+                                    .format("%a %d.%m %H:%M")
+                                    .to_string(),
+                            ),
+                    );
+                }
+            } else {
+                message.push_str(
+                    localization["schedule_cmd"]["not_subscribed"]
+                        .as_str()
+                        .unwrap_or("Default message"),
+                );
+            }
+            message.push_str(
+                localization["schedule_cmd"]["usage"]
+                    .as_str()
+                    .unwrap_or("Default message"),
+            );
+
+            bot.send_message(msg.chat.id, message)
+                .parse_mode(ParseMode::Html)
+                .await?;
+        }
+        NewsWizardCommands::Pause(args) => {
+            info!("Pause cmd used by {}: {}", username, args);
+
+            let message = match args.trim().parse::<u32>() {
+                Ok(days) if (1..=365).contains(&days) => {
+                    match scheduler.pause(msg.chat.id, days)? {
+                        Some(paused_until) => localization["pause_cmd"]["paused"]
+                            .as_str()
+                            .unwrap_or("Default message")
+                            .replace(
+                                "{}",
+                                &paused_until
+                                    .with_timezone(&data.time_zone())
+                                    .format("%d.%m %H:%M")
+                                    .to_string(),
+                            ),
+                        None => localization["pause_cmd"]["not_scheduled"]
+                            .as_str()
+                            .unwrap_or("Default message")
+                            .to_string(),
+                    }
+                }
+                _ => localization["pause_cmd"]["invalid"]
+                    .as_str()
+                    .unwrap_or("Default message")
+                    .to_string(),
+            };
+            bot.send_message(msg.chat.id, message)
+                .parse_mode(ParseMode::Html)
+                .await?;
+        }
+        NewsWizardCommands::Resume => {
+            info!("Resume cmd used by {}", username);
+
+            let message = if scheduler.resume(msg.chat.id)? {
+                localization["resume_cmd"]["resumed"].as_str()
+            } else {
+                localization["resume_cmd"]["not_scheduled"].as_str()
+            };
+            bot.send_message(msg.chat.id, message.unwrap_or("Default message"))
+                .parse_mode(ParseMode::Html)
+                .await?;
+        }
+        NewsWizardCommands::Stop => {
+            info!("Stop cmd used by {}", username);
+
+            let message = if scheduler.stop(msg.chat.id)? {
+                localization["stop_cmd"]["stopped"].as_str()
+            } else {
+                localization["stop_cmd"]["not_scheduled"].as_str()
+            };
+            bot.send_message(msg.chat.id, message.unwrap_or("Default message"))
+                .parse_mode(ParseMode::Html)
+                .await?;
+        }
+        NewsWizardCommands::Sources => {
+            info!("Sources cmd used by {}", username);
+            handle_sources_cmd(
+                bot.clone(),
+                msg.chat.id,
+                &clients,
+                app_state.storage.as_ref(),
                 &language_code,
             )
             .await?;
         }
-        // NewsWizardCommands::SignOut => {
-        //     if let Some(client) = state.client.as_ref() {
-        //         client.sign_out().await?;
-        //         bot.send_message(
-        //             msg.chat.id,
-        //             localization["signout_cmd"]["session_ended"]
-        //                 .as_str()
-        //                 .unwrap_or("Default message"),
-        //         )
-        //         .await?;
-        //     } else {
-        //         bot.send_message(
-        //             msg.chat.id,
-        //             localization["signout_cmd"]["not_authorized"]
-        //                 .as_str()
-        //                 .unwrap_or("Default message"),
-        //         )
-        //         .await?;
-        //     }
-        // }
+        NewsWizardCommands::Status => {
+            info!("Status cmd used by {}", username);
+
+            let message = match queue.job_of(msg.chat.id) {
+                Some(job) if job.status == JobStatus::Queued => localization["status_cmd"]
+                    ["queued"]
+                    .as_str()
+                    .unwrap_or("Default message")
+                    .replace("{id}", &job.id.to_string())
+                    .replace("{position}", &job.position.to_string()),
+                Some(job) => localization["status_cmd"]["running"]
+                    .as_str()
+                    .unwrap_or("Default message")
+                    .replace("{id}", &job.id.to_string())
+                    .replace(
+                        "{stage}",
+                        localization["status_cmd"]["stages"][job.status.as_str()]
+                            .as_str()
+                            .unwrap_or(job.status.as_str()),
+                    ),
+                None => localization["status_cmd"]["no_job"]
+                    .as_str()
+                    .unwrap_or("Default message")
+                    .to_string(),
+            };
+            bot.send_message(msg.chat.id, message)
+                .parse_mode(ParseMode::Html)
+                .await?;
+        }
+        NewsWizardCommands::Cancel => {
+            info!("Cancel cmd used by {}", username);
+
+            let message = match queue.cancel(msg.chat.id)? {
+                Some(id) => localization["cancel_cmd"]["cancelled"]
+                    .as_str()
+                    .unwrap_or("Default message")
+                    .replace("{id}", &id.to_string()),
+                None => localization["cancel_cmd"]["no_job"]
+                    .as_str()
+                    .unwrap_or("Default message")
+                    .to_string(),
+            };
+            bot.send_message(msg.chat.id, message)
+                .parse_mode(ParseMode::Html)
+                .await?;
+        }
         NewsWizardCommands::GetNews => {
             info!("Getnews cmd used by: {}: Trying to get some news...", username);
 
-            let auth_success = authentication(
+            let auth_result = authentication(
                 bot.clone(),
                 msg.clone(),
                 state,
                 user_id,
-                api_id,
-                api_hash.clone(),
+                &clients,
                 &language_code,
-            ).await?;
+            ).await;
+            app_state.storage.save_auth_stages(user_id, state)?;
+            let auth_success = auth_result?;
 
             if auth_success {
                 info!("Getnews cmd: Authentication passed...");
-                handle_getnews_cmd(bot.clone(), msg.clone(), &language_code).await?;
-                info!("Getnews cmd: Podcast created and sent");
-                schedule_daily_getnews_task(bot.clone(), msg, language_code.clone()).await;
-                info!("Getnews cmd: Daily getnews task scheduled");
+
+                let job = match queue.enqueue(
+                    msg.chat.id,
+                    language_code.clone(),
+                    data.time_zone(),
+                    DEFAULT_LOOKBACK,
+                )? {
+                    Enqueued::Added(job) => {
+                        let message = localization["getnews_cmd"]["queued"]
+                            .as_str()
+                            .unwrap_or("Default message")
+                            .replace("{id}", &job.id.to_string())
+                            .replace("{position}", &job.position.to_string());
+                        bot.send_message(msg.chat.id, message)
+                            .parse_mode(ParseMode::Html)
+                            .await?;
+                        job
+                    }
+                    Enqueued::AlreadyQueued(job) => {
+                        let message = localization["getnews_cmd"]["already_queued"]
+                            .as_str()
+                            .unwrap_or("Default message")
+                            .replace("{id}", &job.id.to_string());
+                        bot.send_message(msg.chat.id, message)
+                            .parse_mode(ParseMode::Html)
+                            .await?;
+                        return Ok(());
+                    }
+                };
+
+                let schedule = DeliverySchedule {
+                    chat_id: msg.chat.id,
+                    language_code: language_code.clone(),
+                    rules: data.delivery_rules(),
+                    time_zone: data.time_zone(),
+                    paused_until: None,
+                };
+
+                // A /stop or /schedule sent while the podcast is made is not overwritten
+                scheduler.subscribe_when_done(job.updates, schedule);
             } else {
                 info!("Getnews cmd: User is not authenticated, sent auth request message.");
             }
@@ -217,6 +618,8 @@ pub(crate) async fn message_handler(
     bot: Bot,
     update: Update,
     app_state: Arc<AppState>,
+    clients: Arc<ClientPool>,
+    llm: Arc<dyn LlmProvider>,
 ) -> Result<()> {
     let msg = match update {
         Update {
@@ -231,18 +634,79 @@ pub(crate) async fn message_handler(
     };
 
     let user_id = msg.from.as_ref().map(|user| user.id.0).unwrap_or(0);
-    let mut user_state = app_state.user_state.lock().await;
-    let user_data = app_state.user_data.lock().await;
-
-    if let Some(state) = user_state.get_mut(&user_id) {
-        if state.awaiting_phone_number || state.awaiting_passcode || state.awaiting_2fa {
-            let language_code = user_data
-                .get(&user_id)
-                .and_then(|data| data.language_code.clone())
-                .unwrap_or("ru".to_string());
-            
-            return session_file_creation(bot, msg, state, language_code).await;
+    let Some(session) = app_state.existing_session(user_id) else {
+        return Ok(());
+    };
+    let mut session = session.lock().await;
+    let UserSession { state, data } = &mut *session;
+
+    if state.awaiting_phone_number || state.awaiting_passcode || state.awaiting_2fa {
+        let language_code = data.language_code.clone().unwrap_or("ru".to_string());
+
+        // The client and login tokens live only in memory, so they are gone after a restart
+        if state.client.is_none() {
+            let restore_result = restore_interrupted_login(
+                bot.clone(),
+                msg.clone(),
+                state,
+                user_id,
+                &clients,
+                &language_code,
+            )
+            .await;
+            app_state.storage.save_auth_stages(user_id, state)?;
+            if !restore_result? {
+                return Ok(());
+            }
         }
+
+        let result = session_file_creation(bot, msg, state, &clients, llm.as_ref(), language_code).await;
+        // The login is over one way or another, the pool takes care of the client from now on
+        if !(state.awaiting_phone_number || state.awaiting_passcode || state.awaiting_2fa) {
+            state.client = None;
+        }
+        app_state.storage.save_auth_stages(user_id, state)?;
+        return result;
     }
     Ok(())
 }
+
+pub(crate) async fn callback_handler(
+    bot: Bot,
+    query: CallbackQuery,
+    app_state: Arc<AppState>,
+) -> Result<()> {
+    let user_id = query.from.id.0;
+
+    if let (Some(callback), Some(message)) = (
+        query.data.as_deref().and_then(SourcesCallback::decode),
+        query.message.as_ref(),
+    ) {
+        let language_code = match app_state
+            .session(user_id)
+            .lock()
+            .await
+            .data
+            .language_code
+            .as_deref()
+        {
+            Some("ru") => "ru".to_string(),
+            _ => "en".to_string(),
+        };
+
+        let keyboard = apply_sources_callback(
+            app_state.storage.as_ref(),
+            user_id,
+            callback,
+            &language_code,
+        )?;
+        if let Some(keyboard) = keyboard {
+            bot.edit_message_reply_markup(message.chat().id, message.id())
+                .reply_markup(keyboard)
+                .await?;
+        }
+    }
+
+    bot.answer_callback_query(query.id).await?;
+    Ok(())
+}
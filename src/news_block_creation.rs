@@ -1,39 +1,137 @@
-use std::path::PathBuf;
-
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 use grammers_client::Client;
-use std::fs;
-use teloxide::prelude::Message;
-use tracing::info;
+use teloxide::types::ChatId;
 
-use crate::ai_utils::text_to_speech;
+use crate::ai_utils::{text_to_speech, PodcastAudio};
+use crate::llm::LlmProvider;
+use crate::pipeline::{DebugArtifacts, Progress, ProgressEvent, Stage};
+use crate::speech::SpeechSynthesizer;
+use crate::sources::select_sources;
+use crate::storage::Repository;
 use crate::news_block_creation_utils::{
-    get_dialogs, processing_dialogs, summarize_updates, updates_file_creation,
+    digest_updates, get_dialogs, processing_dialogs, summarize_updates, Lookback,
+    FIRST_RUN_LOOKBACK,
 };
+use std::collections::HashMap;
 
-pub(crate) async fn news_block_creation(client: &Client, msg: Message) -> anyhow::Result<PathBuf> {
-    let channels = get_dialogs(&client).await?;
+/// A podcast ready to be sent.
+pub(crate) struct NewsBlock {
+    pub audio: PodcastAudio,
+    /// Titles of the sources that couldn't be read.
+    pub failed_sources: Vec<String>,
+    /// Id of the newest message the podcast covers, by chat id; saved as seen once it's delivered.
+    pub newest_seen: Vec<(i64, i32)>,
+}
 
-    processing_dialogs(&client, channels, msg.clone()).await?;
+/// What making the podcast came to.
+pub(crate) enum NewsOutcome {
+    Podcast(NewsBlock),
+    /// The sources have nothing new since `since`, so nothing was summarized or recorded.
+    NothingNew {
+        since: DateTime<Utc>,
+        failed_sources: Vec<String>,
+    },
+    /// Not a single source could be read and summarized, the titles of the failed ones.
+    AllSourcesFailed(Vec<String>),
+}
 
-    updates_file_creation(msg.clone()).await?;
+/// Makes the podcast from the sources that could be read.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn news_block_creation(
+    client: &Client,
+    llm: &dyn LlmProvider,
+    speech: &dyn SpeechSynthesizer,
+    storage: &dyn Repository,
+    user_id: u64,
+    time_zone: Tz,
+    lookback: Lookback,
+    progress: &dyn Progress,
+) -> anyhow::Result<NewsOutcome> {
+    progress.report(ProgressEvent::Stage(Stage::Fetching));
+    let channels = select_sources(storage, user_id, get_dialogs(client).await?)?;
+    progress.report(ProgressEvent::SourcesFound(channels.len()));
 
-    let podcast_text = summarize_updates(msg.clone()).await?;
+    let last_seen = match lookback {
+        Lookback::SinceLastSeen => storage.load_last_seen(user_id)?,
+        Lookback::Window(_) => HashMap::new(),
+    };
 
-    let audio_path = text_to_speech(podcast_text, msg.clone()).await?;
+    let debug = DebugArtifacts::new(user_id);
 
-    let user_id = msg.from.as_ref().map(|user| user.id.0).unwrap_or(0);
-    let user_tmp_dir = format!("tmp/{}", user_id);
+    let (updates, mut failed_sources) =
+        processing_dialogs(client, llm, channels, lookback, &last_seen, progress).await;
+    for update in &updates {
+        debug.save(&format!("update_{}.txt", update.chat_id), &update.to_prompt());
+    }
+
+    // A podcast made of nothing would still cost the model and the speech calls
+    if updates.is_empty() && !failed_sources.is_empty() {
+        return Ok(NewsOutcome::AllSourcesFailed(failed_sources));
+    }
+    if updates.iter().all(|update| update.messages.is_empty()) {
+        return Ok(NewsOutcome::NothingNew {
+            since: nothing_new_since(storage, user_id, lookback)?,
+            failed_sources,
+        });
+    }
 
-    let txt_files: Vec<_> = fs::read_dir(&user_tmp_dir)?
-        .filter_map(Result::ok)
-        .filter(|entry| entry.path().extension().map_or(false, |ext| ext == "txt"))
-        .map(|entry| entry.path())
-        .collect();
+    progress.report(ProgressEvent::Stage(Stage::Summarizing));
+    let (digests, failed_ids) = digest_updates(llm, &updates, progress).await;
+    failed_sources.extend(
+        updates
+            .iter()
+            .filter(|update| failed_ids.contains(&update.chat_id))
+            .map(|update| update.title.clone()),
+    );
+    let digests_text: Vec<String> = digests.iter().map(|digest| digest.to_text()).collect();
+    debug.save("updates.txt", &digests_text.join("\n\n"));
 
-    for file_path in &txt_files {
-        fs::remove_file(file_path)?;
-        info!("File {} has been deleted.", file_path.display());
+    if updates.iter().all(|update| failed_ids.contains(&update.chat_id)) {
+        return Ok(NewsOutcome::AllSourcesFailed(failed_sources));
     }
+    if digests.is_empty() {
+        return Ok(NewsOutcome::NothingNew {
+            since: nothing_new_since(storage, user_id, lookback)?,
+            failed_sources,
+        });
+    }
+
+    let script = summarize_updates(llm, time_zone, lookback, &digests).await?;
+    debug.save("updates_summarized.txt", &script.text);
+
+    progress.report(ProgressEvent::Stage(Stage::Speaking));
+    let audio = text_to_speech(speech, &script, user_id).await?;
+
+    // Recaps retell what was already heard, they don't move the regular podcast forward.
+    // The updates of a failed source are picked up by the next podcast.
+    let newest_seen = if lookback == Lookback::SinceLastSeen {
+        updates
+            .iter()
+            .filter(|update| !failed_ids.contains(&update.chat_id))
+            .filter_map(|update| Some((update.chat_id, update.newest?)))
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    Ok(NewsOutcome::Podcast(NewsBlock {
+        audio,
+        failed_sources,
+        newest_seen,
+    }))
+}
 
-    Ok(audio_path)
+/// Where the updates the podcast looked for start: at the last podcast for a regular one.
+fn nothing_new_since(
+    storage: &dyn Repository,
+    user_id: u64,
+    lookback: Lookback,
+) -> anyhow::Result<DateTime<Utc>> {
+    Ok(match lookback {
+        Lookback::SinceLastSeen => storage
+            .last_done_job_at(ChatId(user_id as i64))?
+            .unwrap_or_else(|| Utc::now() - FIRST_RUN_LOOKBACK),
+        Lookback::Window(window) => Utc::now() - window,
+    })
 }
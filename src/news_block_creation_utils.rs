@@ -1,240 +1,695 @@
-use crate::ai_utils::llm_processing;
+use crate::common_utils::positive_from_env;
+use crate::llm::{complete_structured, LlmProvider, StructuredOutput};
+use crate::pipeline::{
+    ChannelDigest, ChannelUpdate, PodcastScript, Progress, ProgressEvent, Stage,
+};
+use crate::relevance::classify_messages;
+use crate::sources::{chat_title, source_kind, SourceKind};
+use crate::telegram::telegram_next;
+use crate::tokens::{count_tokens, truncate_to_tokens};
 use chrono::{Duration, Utc};
+use chrono_tz::Tz;
+use futures::future::join_all;
 use grammers_client::{types, Client};
 use log::info;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::fs;
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::path::Path;
-use std::time::Duration as Duration_2;
-use teloxide::prelude::Message;
-use tokio::time::sleep;
+use tokio::sync::Semaphore;
+
+/// Which updates of a source make it into the podcast.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Lookback {
+    /// Updates newer than the last one that made it into a podcast, but no older than `max_lookback`.
+    SinceLastSeen,
+    /// Every update of the period, no matter whether it was in a podcast already.
+    Window(Duration),
+}
+
+/// A regular podcast picks up where the previous one stopped.
+pub(crate) const DEFAULT_LOOKBACK: Lookback = Lookback::SinceLastSeen;
+/// A weekly recap podcast retells the whole week.
+pub(crate) const RECAP_LOOKBACK: Lookback = Lookback::Window(Duration::days(7));
+/// How far back the first podcast of a source looks, when there's nothing seen yet.
+pub(crate) const FIRST_RUN_LOOKBACK: Duration = Duration::hours(9);
+const DEFAULT_MAX_LOOKBACK_HOURS: i64 = 72;
+/// Room for the updates in one prompt, the system role and the answer need some too.
+const DEFAULT_MAX_PROMPT_TOKENS: usize = 60_000;
+/// Condensing rounds before the updates that still don't fit are left out.
+const MAX_REDUCE_ROUNDS: usize = 3;
+/// Sources whose messages are fetched at once, per podcast.
+const DEFAULT_TELEGRAM_CONCURRENCY: usize = 4;
+
+/// Group chats can be much busier than channels, only the latest messages make it into the podcast.
+const MAX_DISCUSSION_MESSAGES: usize = 300;
+
+/// The oldest update a podcast picks up after a long break, `MAX_LOOKBACK_HOURS` env var.
+fn max_lookback() -> Duration {
+    Duration::hours(positive_from_env("MAX_LOOKBACK_HOURS", DEFAULT_MAX_LOOKBACK_HOURS))
+}
+
+/// The updates of one source, retold by the model.
+#[derive(Debug, Deserialize)]
+pub(crate) struct SourceSummary {
+    pub source: String,
+    pub items: Vec<String>,
+}
+
+impl StructuredOutput for SourceSummary {
+    const NAME: &'static str = "source_summary";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "source": { "type": "string" },
+                "items": { "type": "array", "items": { "type": "string" } },
+            },
+            "required": ["source", "items"],
+            "additionalProperties": false,
+        })
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.source.trim().is_empty() {
+            return Err("\"source\" is empty".to_string());
+        }
+        Ok(())
+    }
+
+    /// Retells the request as it is, under the title it starts with.
+    fn mock_example(request: &str) -> Value {
+        // `Источник: {title}` or `Чат: {title}`
+        let source = request
+            .lines()
+            .next()
+            .and_then(|line| line.split_once(": "))
+            .map(|(_, title)| title.trim())
+            .filter(|title| !title.is_empty())
+            .unwrap_or("Mock");
+        json!({ "source": source, "items": [request.trim()] })
+    }
+}
+
+/// How many tokens of updates go into one prompt, `MAX_PROMPT_TOKENS` env var.
+fn max_prompt_tokens() -> usize {
+    positive_from_env("MAX_PROMPT_TOKENS", DEFAULT_MAX_PROMPT_TOKENS)
+}
 
 pub(crate) async fn get_dialogs(client: &Client) -> Result<Vec<types::Dialog>, anyhow::Error> {
     info!("Getting list of groups, channels and dialogues...");
 
     let mut dialogs = client.iter_dialogs();
-    // let mut groups = Vec::new();
-    let mut channels = Vec::new();
-    // let mut private_chats = Vec::new();
+    let mut taken = 0;
+    let mut sources = Vec::new();
 
-    while let Some(dialog) = dialogs.next().await? {
+    while let Some(dialog) = telegram_next!(dialogs, taken)? {
         match dialog.chat() {
             types::Chat::Group(group) => {
-                // groups.push(dialog.clone());
                 info!("Group: {} (ID: {})", group.title(), group.id());
             }
             types::Chat::Channel(channel) => {
-                channels.push(dialog.clone());
                 info!("Channel: {} (ID: {})", channel.title(), channel.id());
             }
             types::Chat::User(user) => {
-                // private_chats.push(dialog.clone());
                 info!("Private chat: {} (ID: {})", user.first_name(), user.id());
             }
         }
+
+        if source_kind(dialog.chat()).is_some() {
+            sources.push(dialog);
+        }
     }
-    Ok(channels)
+    Ok(sources)
 }
 
+/// Where fetching the updates of a source stops: at the last message seen or at the start of the
+/// period, whichever comes first.
+pub(crate) struct Since {
+    message_id: Option<i32>,
+    period: Duration,
+}
+
+/// Fetches the updates of every source. A source that fails doesn't stop the others, the titles
+/// of the failed ones are returned next to the fetched ones.
 pub(crate) async fn processing_dialogs(
     client: &Client,
-    channels: Vec<types::Dialog>,
-    msg: Message,
-) -> Result<(), anyhow::Error> {
-    // info!("\nReceiving updates from each group...\n");
-    // for dialog in groups {
-    //     if let types::Chat::Group(group) = dialog.chat() {
-    //         let group_name = group.title();
-    //         info!("\nGroup: {}\n", group_name);
-    //         get_latest_messages(client, dialog.clone(), &group_name, msg.clone()).await?;
-    //         sleep(Duration_2::from_secs(2)).await;
-    //     }
-    // }
-
-    info!("\nReceiving updates from each channel...");
-    for dialog in channels {
-        if let types::Chat::Channel(channel) = dialog.chat() {
-            let channel_name = channel.title();
-            info!("\nChannel: {}\n", channel_name);
-            get_latest_messages(client, dialog.clone(), &channel_name, msg.clone()).await?;
-            sleep(Duration_2::from_secs(2)).await;
-        }
-    }
-
-    // info!("\nReceiving updates from each private chat...");
-    // for dialog in private_chats {
-    //     if let types::Chat::User(user) = dialog.chat() {
-    //         let user_name = match (user.first_name(), user.last_name()) {
-    //             (first, Some(last)) => format!("{} {}", first, last),
-    //             (first, None) => first.to_string(),
-    //         };
-    //         info!("\nPrivate chat: {}\n", user_name);
-    //         get_latest_messages(client, dialog.clone(), &user_name, msg.clone()).await?;
-    //         sleep(Duration_2::from_secs(2)).await;
-    //     }
-    // }
-    Ok(())
-}
-
-pub(crate) async fn updates_file_creation(msg: Message) -> Result<(), anyhow::Error> {
-    info!("\nAppealing to information sources and record the results in updates.txt...\n");
-
-    let user_id = msg.from.as_ref().map(|user| user.id.0).unwrap_or(0);
-    let user_tmp_dir = format!("tmp/{}", user_id);
-
-    let updates_file_path = format!("{}/updates.txt", user_tmp_dir);
-    let mut updates_file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .append(true)
-        .open(updates_file_path.clone())?;
-
-    remove_empty_txt_files(user_tmp_dir.clone()).await?;
-
-    let txt_files: Vec<_> = fs::read_dir(&user_tmp_dir)?
-        .filter_map(Result::ok)
-        .filter(|entry| entry.path().extension().map_or(false, |ext| ext == "txt"))
-        .filter(|entry| entry.path() != Path::new(&updates_file_path))
-        .map(|entry| entry.path())
-        .collect();
-
-    let now = Utc::now();
-    let utc_plus_3 = now + Duration::hours(3);
+    llm: &dyn LlmProvider,
+    dialogs: Vec<types::Dialog>,
+    lookback: Lookback,
+    last_seen: &HashMap<i64, i32>,
+    progress: &dyn Progress,
+) -> (Vec<ChannelUpdate>, Vec<String>) {
+    info!("\nReceiving updates from each source...");
+    let telegram = Semaphore::new(positive_from_env(
+        "TELEGRAM_CONCURRENCY",
+        DEFAULT_TELEGRAM_CONCURRENCY,
+    ));
+
+    let sources = dialogs.into_iter().filter_map(|dialog| {
+        let kind = source_kind(dialog.chat())?;
+        let telegram = &telegram;
+        Some(async move {
+            let chat_id = dialog.chat().id();
+            let chat_name = chat_title(dialog.chat());
+            info!("\n{:?}: {}\n", kind, chat_name);
+
+            let since = match lookback {
+                Lookback::SinceLastSeen => Since {
+                    message_id: last_seen.get(&chat_id).copied(),
+                    period: if last_seen.contains_key(&chat_id) {
+                        max_lookback()
+                    } else {
+                        FIRST_RUN_LOOKBACK
+                    },
+                },
+                Lookback::Window(period) => Since {
+                    message_id: None,
+                    period,
+                },
+            };
+
+            let fetched = get_latest_messages(
+                client, llm, telegram, dialog, &chat_name, kind, since, progress,
+            )
+            .await;
+            match fetched {
+                Ok((messages, newest)) => {
+                    progress.report(ProgressEvent::SourceRead {
+                        kept: messages.len(),
+                    });
+                    Ok(ChannelUpdate {
+                        chat_id,
+                        title: chat_name,
+                        kind,
+                        messages,
+                        newest,
+                    })
+                }
+                Err(e) => {
+                    info!("Failed to read {} (ID: {}): {:?}", chat_name, chat_id, e);
+                    progress.report(ProgressEvent::SourceFailed);
+                    Err(chat_name)
+                }
+            }
+        })
+    });
+
+    let mut fetched = Vec::new();
+    let mut failed = Vec::new();
+    for outcome in join_all(sources).await {
+        match outcome {
+            Ok(source) => fetched.push(source),
+            Err(title) => failed.push(title),
+        }
+    }
+    (fetched, failed)
+}
 
-    writeln!(
-        updates_file,
-        "\nДата и время формирования обновлений: {}",
-        utc_plus_3
-    )?;
+/// Summarizes the updates of every source, returns the digests and the ids of the sources that
+/// couldn't be summarized. Sources with nothing new are left out.
+pub(crate) async fn digest_updates(
+    llm: &dyn LlmProvider,
+    updates: &[ChannelUpdate],
+    progress: &dyn Progress,
+) -> (Vec<ChannelDigest>, Vec<i64>) {
+    info!("\nAppealing to information sources...\n");
 
     let system_role = fs::read_to_string("common_res/system_role.txt")
         .map_err(|e| format!("Failed to read 'system role': {}", e))
         .unwrap();
 
-    for file_path in txt_files.clone() {
-        let content = fs::read_to_string(&file_path)?;
-        let response = llm_processing(system_role.clone(), content).await?;
-        writeln!(updates_file, "\n{}\n", response)?;
-        info!("File {} is ready!", file_path.display());
+    let discussion_system_role = fs::read_to_string("common_res/system_role_5.txt")
+        .map_err(|e| format!("Failed to read 'system role': {}", e))
+        .unwrap();
+
+    let max_tokens = max_prompt_tokens();
+
+    // All sources are summarized at once, the LLM provider limits how many requests really run
+    let outcomes = join_all(
+        updates
+            .iter()
+            .filter(|update| !update.messages.is_empty())
+            .map(|update| {
+                let role = if update.kind.is_discussion() {
+                    &discussion_system_role
+                } else {
+                    &system_role
+                };
+                async move {
+                    let summaries = join_all(
+                        source_prompts(update, max_tokens)
+                            .into_iter()
+                            .map(|prompt| async move {
+                                complete_structured::<SourceSummary>(llm, role, &prompt).await
+                            }),
+                    )
+                    .await;
+                    // The source is summarized only as a whole, a part left out would be
+                    // marked as seen along with the rest
+                    let items = summaries
+                        .into_iter()
+                        .map(|summary| summary.map(|summary| summary.items))
+                        .collect::<Result<Vec<_>, _>>()
+                        .map(|parts| parts.concat());
+                    info!("{} is summarized", update.title);
+                    progress.report(ProgressEvent::SourceSummarized);
+                    (update, items)
+                }
+            }),
+    )
+    .await;
+
+    let mut digests = Vec::new();
+    let mut failed = Vec::new();
+    for (update, outcome) in outcomes {
+        match outcome {
+            Ok(items) if !items.is_empty() => digests.push(ChannelDigest {
+                title: update.title.clone(),
+                items,
+            }),
+            Ok(_) => {}
+            Err(e) => {
+                info!("Failed to summarize {}: {:?}", update.title, e);
+                failed.push(update.chat_id);
+            }
+        }
     }
 
-    writeln!(updates_file, "\nКонец обновлений\n")?;
+    (digests, failed)
+}
 
-    for file_path in &txt_files {
-        fs::remove_file(file_path)?;
-        info!("File {} has been deleted.", file_path.display());
+/// The summary requests of a source, its messages in order split so that every request fits
+/// into `max_tokens`. A message too long on its own is cut.
+fn source_prompts(update: &ChannelUpdate, max_tokens: usize) -> Vec<String> {
+    let prompt = update.to_prompt();
+    if count_tokens(&prompt) <= max_tokens {
+        return vec![prompt];
     }
 
-    Ok(())
-}
+    // Every message is counted along with the whole framing, a bit more than a discussion line
+    // really takes. The edges of a message may tokenize differently inside the framing, a couple
+    // of tokens are kept for them.
+    let frame = count_tokens(&update.prompt_of(&[String::new()])) + 2;
+    let room = max_tokens.saturating_sub(frame).max(1);
+
+    let mut prompts = Vec::new();
+    let mut batch = Vec::new();
+    let mut batch_tokens = 0;
+    for message in &update.messages {
+        let message = truncate_to_tokens(message, room);
+        let tokens = count_tokens(&message) + frame;
+        if !batch.is_empty() && batch_tokens + tokens > max_tokens {
+            prompts.push(update.prompt_of(&batch));
+            batch.clear();
+            batch_tokens = 0;
+        }
+        batch_tokens += tokens;
+        batch.push(message);
+    }
 
-pub(crate) async fn summarize_updates(msg: Message) -> Result<String, anyhow::Error> {
-    let user_id = msg.from.as_ref().map(|user| user.id.0).unwrap_or(0);
-    let user_tmp_dir = format!("tmp/{}", user_id);
+    if !batch.is_empty() {
+        prompts.push(update.prompt_of(&batch));
+    }
+    prompts
+}
 
+/// Turns the digests of the sources into the podcast script, condensing them first when there
+/// are too many for one prompt.
+pub(crate) async fn summarize_updates(
+    llm: &dyn LlmProvider,
+    time_zone: Tz,
+    lookback: Lookback,
+    digests: &[ChannelDigest],
+) -> Result<PodcastScript, anyhow::Error> {
     let system_role_2 = fs::read_to_string("common_res/system_role_2.txt")
         .map_err(|e| format!("Failed to read 'system role': {}", e))
         .unwrap();
+    let system_role_2 = podcast_role(&system_role_2, lookback);
+
+    let summaries = digests.iter().map(ChannelDigest::to_text).collect();
+    let summaries = reduce_updates(llm, summaries, max_prompt_tokens()).await?;
+
+    let created_at = Utc::now().with_timezone(&time_zone);
+    let updates = format!(
+        "\nДата и время формирования обновлений: {}\n\n{}\n\nКонец обновлений\n",
+        created_at,
+        summaries.join("\n\n")
+    );
+
+    let text = llm.complete(&system_role_2, &updates).await?;
+
+    Ok(PodcastScript { created_at, text })
+}
 
-    let updates = fs::read_to_string(format!("{}/updates.txt", user_tmp_dir))
-        .map_err(|e| format!("Failed to read 'updates': {}", e))
+/// Fills the period the updates cover into the podcast role, a recap is told to retell the week.
+fn podcast_role(template: &str, lookback: Lookback) -> String {
+    let (period, recap) = match lookback {
+        Lookback::SinceLastSeen => ("с момента нашего прошлого подкаста".to_string(), ""),
+        Lookback::Window(window) => (
+            format!("за последние {} дней", window.num_days()),
+            " Это еженедельный обзор: расскажи о главных событиях и темах этого периода, а не о каждом обновлении по отдельности.",
+        ),
+    };
+    template
+        .replace("{period}", &period)
+        .replace("{recap}", recap)
+}
+
+/// Condenses the updates batch by batch, round after round, until all of them fit into one prompt.
+async fn reduce_updates(
+    llm: &dyn LlmProvider,
+    mut updates: Vec<String>,
+    max_tokens: usize,
+) -> Result<Vec<String>, anyhow::Error> {
+    let system_role_6 = fs::read_to_string("common_res/system_role_6.txt")
+        .map_err(|e| format!("Failed to read 'system role': {}", e))
         .unwrap();
 
-    let updates_summarized = llm_processing(system_role_2, updates).await?;
+    for round in 1..=MAX_REDUCE_ROUNDS {
+        let total: usize = updates.iter().map(|update| count_tokens(update)).sum();
+        if total <= max_tokens {
+            return Ok(updates);
+        }
 
-    let updates_summarized_file_path = format!("{}/updates_summarized.txt", user_tmp_dir);
+        let batches = batch_by_tokens(updates, max_tokens);
+        info!(
+            "Updates take {} tokens, condensing them in {} batches (round {})",
+            total,
+            batches.len(),
+            round
+        );
+
+        let requests: Vec<String> = batches.iter().map(|batch| batch.join("\n\n")).collect();
+        let condensed = join_all(
+            requests
+                .iter()
+                .map(|request| llm.complete(&system_role_6, request)),
+        )
+        .await;
+
+        // A batch that failed to condense goes on as it is, the next round tries again
+        updates = requests
+            .into_iter()
+            .zip(condensed)
+            .map(|(request, condensed)| {
+                condensed.unwrap_or_else(|e| {
+                    info!("Failed to condense a batch of updates: {:?}", e);
+                    request
+                })
+            })
+            .collect();
+    }
 
-    let mut updates_summarized_file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .append(true)
-        .open(updates_summarized_file_path.clone())?;
+    // Still too much after every round, the podcast goes without the rest
+    let mut total = 0;
+    let fitting: Vec<String> = updates
+        .into_iter()
+        .take_while(|update| {
+            total += count_tokens(update);
+            total <= max_tokens
+        })
+        .collect();
+    info!("Updates don't fit after condensing, {} parts are kept", fitting.len());
+    Ok(fitting)
+}
 
-    writeln!(updates_summarized_file, "{}\n", updates_summarized)?;
+/// Groups the updates in order, so that every group joined with blank lines fits into
+/// `max_tokens`, an update too long on its own is cut.
+fn batch_by_tokens(updates: Vec<String>, max_tokens: usize) -> Vec<Vec<String>> {
+    // The blank line joining an update to the others, the edges of the update may tokenize
+    // differently next to it
+    const JOIN_TOKENS: usize = 3;
+
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    let mut batch_tokens = 0;
+
+    for update in updates {
+        let update = truncate_to_tokens(&update, max_tokens.saturating_sub(JOIN_TOKENS).max(1));
+        let tokens = count_tokens(&update) + JOIN_TOKENS;
+        if !batch.is_empty() && batch_tokens + tokens > max_tokens {
+            batches.push(std::mem::take(&mut batch));
+            batch_tokens = 0;
+        }
+        batch_tokens += tokens;
+        batch.push(update);
+    }
 
-    Ok(updates_summarized)
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
 }
 
+/// Fetches the messages of a source while holding a `telegram` permit, returns the texts of the
+/// ones worth a podcast and the id of the newest one seen.
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn get_latest_messages(
     client: &Client,
+    llm: &dyn LlmProvider,
+    telegram: &Semaphore,
     dialog: types::Dialog,
     chat_name: &str,
-    msg: Message,
-) -> anyhow::Result<()> {
-    let user_id = msg.from.as_ref().map(|user| user.id.0).unwrap_or(0);
+    kind: SourceKind,
+    since: Since,
+    progress: &dyn Progress,
+) -> anyhow::Result<(Vec<String>, Option<i32>)> {
+    let fetching = telegram.acquire().await?;
     let mut messages = client.iter_messages(dialog.chat());
+    let mut taken = 0;
     let now = Utc::now();
-    let period = now - Duration::hours(9);
-
-    let user_tmp_dir = format!("tmp/{}", user_id);
-    fs::create_dir_all(&user_tmp_dir)?;
-
-    let file_name = format!(
-        "tmp/{}/{}.txt",
-        user_id,
-        chat_name.replace(" ", "_").replace("/", "_")
-    );
+    let period = now - since.period;
+    let mut newest = None;
+
+    if kind.is_discussion() {
+        // Chatter is kept as is and in chronological order, it's summarized as a whole later on
+        let mut discussion = Vec::new();
+        while let Some(message) = telegram_next!(messages, taken)? {
+            if message.date() < period
+                || since.message_id.is_some_and(|id| message.id() <= id)
+                || discussion.len() >= MAX_DISCUSSION_MESSAGES
+            {
+                break;
+            }
+            newest.get_or_insert(message.id());
+            if !message.text().is_empty() {
+                let author = message
+                    .sender()
+                    .map(|sender| chat_title(&sender))
+                    .unwrap_or_else(|| chat_name.to_string());
+                discussion.push(format!("{}: {}", author, message.text()));
+            }
+        }
 
-    let mut file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .open(file_name)?;
+        discussion.reverse();
+        return Ok((discussion, newest));
+    }
 
-    let system_role = fs::read_to_string("common_res/system_role_4.txt")
-        .map_err(|e| format!("Failed to read 'system role': {}", e))
-        .unwrap();
-    
-    while let Some(message) = messages.next().await? {
-        if message.date() < period {
+    let mut candidates = Vec::new();
+    while let Some(message) = telegram_next!(messages, taken)? {
+        if message.date() < period || since.message_id.is_some_and(|id| message.id() <= id) {
             break;
         }
+        newest.get_or_insert(message.id());
         if !message.text().is_empty() {
-            let text = message.text().to_string();
-            info!("Checking message via LLM: {}", text);
-            // Check an update for a useful info via LLM
-            let llm_response = llm_processing(system_role.clone(), text.clone()).await?;
-
-            if llm_response.trim() == "skip" {
-                info!("Message passed after processing through LLM.");
-                continue;
+            candidates.push((message.id(), message.text().to_string()));
+        }
+    }
+    drop(fetching);
+
+    // Check the updates for a useful info via LLM
+    progress.report(ProgressEvent::Stage(Stage::Classifying));
+    let verdicts = classify_messages(llm, &candidates).await?;
+    info!(
+        "{} of {} messages passed the LLM check",
+        verdicts.iter().filter(|ok| **ok).count(),
+        candidates.len()
+    );
+
+    let texts = candidates
+        .into_iter()
+        .zip(verdicts)
+        .filter_map(|((_, text), ok)| ok.then_some(text))
+        .collect();
+
+    Ok((texts, newest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::MockProvider;
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts the summarized sources.
+    #[derive(Default)]
+    struct Summarized(AtomicUsize);
+
+    impl Progress for Summarized {
+        fn report(&self, event: ProgressEvent) {
+            if let ProgressEvent::SourceSummarized = event {
+                self.0.fetch_add(1, Ordering::SeqCst);
             }
-            
-            writeln!(
-                file,
-                "Источник: {}\nНачало обновления:\n{}\nКонец обновления.",
-                dialog.chat.name(),
-                text
-            )?;
+        }
+    }
 
-            writeln!(file, "\n***\n")?;
+    fn update(kind: SourceKind, messages: Vec<String>) -> ChannelUpdate {
+        ChannelUpdate {
+            chat_id: 1,
+            title: "Новости".to_string(),
+            kind,
+            messages,
+            newest: Some(10),
         }
     }
-    
-    // writeln!(file, "\nКонец обновлений\n")?;
 
-    Ok(())
-}
+    #[test]
+    fn a_small_source_is_one_prompt() {
+        let update = update(SourceKind::Channel, vec!["Первая".into(), "Вторая".into()]);
 
-async fn remove_empty_txt_files(dir: String) -> anyhow::Result<()> {
-    for entry in fs::read_dir(dir)? {
-        let entry = entry?;
-        let path = entry.path();
+        assert_eq!(source_prompts(&update, 1000), vec![update.to_prompt()]);
+    }
 
-        if let Some(file_name) = path.file_name() {
-            if file_name == "updates.txt" {
-                continue;
+    #[test]
+    fn a_large_source_is_split_into_prompts_that_fit() {
+        for kind in [SourceKind::Channel, SourceKind::Group] {
+            let messages: Vec<String> = (0..40)
+                .map(|i| format!("Сообщение {} {}", i, "слово ".repeat(30)))
+                .collect();
+            let update = update(kind, messages.clone());
+
+            let prompts = source_prompts(&update, 500);
+
+            assert!(prompts.len() > 1);
+            assert!(prompts.iter().all(|prompt| count_tokens(prompt) <= 500));
+            // Every message is kept, in order
+            let mut rest = prompts.concat();
+            for message in &messages {
+                let at = rest.find(message.as_str()).expect("a message is lost");
+                rest = rest[at + message.len()..].to_string();
             }
         }
+    }
 
-        if path.extension().and_then(|ext| ext.to_str()) == Some("txt") {
-            let metadata = fs::metadata(&path)?;
+    #[test]
+    fn a_message_too_long_on_its_own_is_cut() {
+        let update = update(SourceKind::Channel, vec!["слово ".repeat(2000)]);
 
-            if metadata.len() == 0 {
-                fs::remove_file(&path)?;
-                println!("Deleted empty file: {:?}", path);
-            }
+        let prompts = source_prompts(&update, 300);
+
+        assert_eq!(prompts.len(), 1);
+        assert!(count_tokens(&prompts[0]) <= 300);
+    }
+
+    /// Answers with the request itself and counts the requests.
+    #[derive(Default)]
+    struct Echo(AtomicUsize);
+
+    #[async_trait]
+    impl LlmProvider for Echo {
+        async fn complete(&self, _system_role: &str, request: &str) -> anyhow::Result<String> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(request.to_string())
+        }
+
+        async fn complete_json(
+            &self,
+            _system_role: &str,
+            _request: &str,
+            _name: &str,
+            _schema: &Value,
+        ) -> anyhow::Result<String> {
+            anyhow::bail!("Echo gives no JSON")
         }
     }
-    Ok(())
+
+    fn digest(index: usize, words: usize) -> String {
+        format!("Источник {}:\n{}", index, "новость ".repeat(words))
+    }
+
+    #[test]
+    fn batches_never_go_over_the_budget() {
+        let updates: Vec<String> = (0..30).map(|i| digest(i, 5 + i * 7 % 40)).collect();
+
+        let batches = batch_by_tokens(updates.clone(), 120);
+
+        assert!(batches.len() > 1);
+        for batch in &batches {
+            assert!(count_tokens(&batch.join("\n\n")) <= 120);
+        }
+        assert_eq!(batches.concat(), updates);
+    }
+
+    #[tokio::test]
+    async fn an_update_longer_than_the_budget_is_cut_once() {
+        let llm = Echo::default();
+        let long = digest(1, 2000);
+
+        let batches = batch_by_tokens(vec![long.clone()], 100);
+        assert_eq!(batches.len(), 1);
+        assert!(count_tokens(&batches[0][0]) <= 100);
+
+        let reduced = reduce_updates(&llm, vec![long], 100).await.unwrap();
+        assert_eq!(reduced.len(), 1);
+        assert!(count_tokens(&reduced[0]) <= 100);
+        assert_eq!(llm.0.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn reduce_is_skipped_when_everything_fits() {
+        let llm = Echo::default();
+        let updates: Vec<String> = (0..3).map(|i| digest(i, 10)).collect();
+
+        let reduced = reduce_updates(&llm, updates.clone(), 1000).await.unwrap();
+
+        assert_eq!(reduced, updates);
+        assert_eq!(llm.0.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn podcast_role_tells_the_period_of_the_updates() {
+        let template = fs::read_to_string("common_res/system_role_2.txt").unwrap();
+
+        let regular = podcast_role(&template, DEFAULT_LOOKBACK);
+        let recap = podcast_role(&template, RECAP_LOOKBACK);
+
+        assert!(regular.contains("каналов с момента нашего прошлого подкаста."));
+        assert!(!regular.contains("еженедельный"));
+        assert!(recap.contains("каналов за последние 7 дней. Это еженедельный обзор"));
+        assert!(!regular.contains('{') && !recap.contains('{'));
+    }
+
+    #[tokio::test]
+    async fn digest_updates_runs_on_the_mock_provider() {
+        let updates = vec![
+            update(SourceKind::Channel, vec!["Дождь в Москве".into()]),
+            ChannelUpdate {
+                chat_id: 2,
+                title: "Соседи".to_string(),
+                kind: SourceKind::Group,
+                messages: vec!["Анна: привет".into(), "Борис: привет".into()],
+                newest: Some(20),
+            },
+            ChannelUpdate {
+                chat_id: 3,
+                title: "Тишина".to_string(),
+                kind: SourceKind::Channel,
+                messages: Vec::new(),
+                newest: None,
+            },
+        ];
+        let progress = Summarized::default();
+
+        let (digests, failed) =
+            digest_updates(&MockProvider::new(None), &updates, &progress).await;
+
+        assert!(failed.is_empty());
+        let titles: Vec<&str> = digests.iter().map(|digest| digest.title.as_str()).collect();
+        assert_eq!(titles, ["Новости", "Соседи"]);
+        assert!(digests[0].items[0].contains("Дождь в Москве"));
+        assert_eq!(progress.0.load(Ordering::SeqCst), 2);
+    }
 }
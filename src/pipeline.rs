@@ -0,0 +1,149 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use log::info;
+
+use crate::sources::SourceKind;
+
+/// The steps of making a podcast, in the order they come.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    Fetching,
+    Classifying,
+    Summarizing,
+    Speaking,
+}
+
+impl Stage {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Fetching => "fetching",
+            Stage::Classifying => "classifying",
+            Stage::Summarizing => "summarizing",
+            Stage::Speaking => "speaking",
+        }
+    }
+
+    pub(crate) fn from_str(value: &str) -> Option<Self> {
+        match value {
+            "fetching" => Some(Stage::Fetching),
+            "classifying" => Some(Stage::Classifying),
+            "summarizing" => Some(Stage::Summarizing),
+            "speaking" => Some(Stage::Speaking),
+            _ => None,
+        }
+    }
+}
+
+/// Something that happened while making a podcast.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum ProgressEvent {
+    /// Sources are fetched and classified concurrently, so a stage may come again after a later one.
+    Stage(Stage),
+    /// The number of sources the podcast is made from.
+    SourcesFound(usize),
+    /// A source was read, `kept` of its messages are worth the podcast.
+    SourceRead { kept: usize },
+    SourceFailed,
+    SourceSummarized,
+}
+
+/// Gets told how making a podcast goes.
+pub(crate) trait Progress: Send + Sync {
+    fn report(&self, event: ProgressEvent);
+}
+
+/// The fresh messages of one source, as fetched from Telegram.
+pub(crate) struct ChannelUpdate {
+    pub chat_id: i64,
+    pub title: String,
+    pub kind: SourceKind,
+    /// Texts worth a podcast: news posts newest first, `author: text` lines of discussions oldest
+    /// first.
+    pub messages: Vec<String>,
+    /// Id of the newest message seen, `None` when there was nothing new.
+    pub newest: Option<i32>,
+}
+
+impl ChannelUpdate {
+    /// The request for the summary, in the layout the system roles expect.
+    pub(crate) fn to_prompt(&self) -> String {
+        self.prompt_of(&self.messages)
+    }
+
+    /// The request for the summary of a part of the messages.
+    pub(crate) fn prompt_of(&self, messages: &[String]) -> String {
+        if self.kind.is_discussion() {
+            return format!(
+                "Чат: {}\nНачало обсуждения:\n{}\nКонец обсуждения.\n",
+                self.title,
+                messages.join("\n")
+            );
+        }
+
+        messages
+            .iter()
+            .map(|text| {
+                format!(
+                    "Источник: {}\nНачало обновления:\n{}\nКонец обновления.\n\n***\n\n",
+                    self.title, text
+                )
+            })
+            .collect()
+    }
+}
+
+/// The updates of one source, retold by the model.
+pub(crate) struct ChannelDigest {
+    pub title: String,
+    pub items: Vec<String>,
+}
+
+impl ChannelDigest {
+    /// The text the podcast prompt gets, in the `Источник: ...` layout it expects.
+    pub(crate) fn to_text(&self) -> String {
+        format!("Источник: {}:\n{}", self.title, self.items.join("\n\n"))
+    }
+}
+
+/// What the podcast host reads out.
+pub(crate) struct PodcastScript {
+    pub created_at: DateTime<Tz>,
+    pub text: String,
+}
+
+/// Keeps the intermediate texts of one podcast in `tmp/{user_id}/debug/{run}/` when the
+/// `DEBUG_ARTIFACTS` env var is set, does nothing otherwise.
+pub(crate) struct DebugArtifacts {
+    dir: Option<PathBuf>,
+}
+
+impl DebugArtifacts {
+    pub(crate) fn new(user_id: u64) -> Self {
+        let enabled = env::var("DEBUG_ARTIFACTS").is_ok_and(|value| value == "1" || value == "true");
+        let dir = enabled.then(|| {
+            PathBuf::from(format!(
+                "tmp/{}/debug/{}",
+                user_id,
+                Utc::now().format("%Y%m%d_%H%M%S_%3f")
+            ))
+        });
+        Self { dir }
+    }
+
+    /// A failed write is only logged, the podcast doesn't depend on it.
+    pub(crate) fn save(&self, name: &str, content: &str) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+
+        let path = dir.join(name);
+        match fs::create_dir_all(dir).and_then(|_| fs::write(&path, content)) {
+            Ok(()) => info!("Debug artifact {} is saved", path.display()),
+            Err(e) => info!("Failed to save {}: {}", path.display(), e),
+        }
+    }
+}
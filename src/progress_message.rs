@@ -0,0 +1,180 @@
+use crate::pipeline::{Progress, ProgressEvent, Stage};
+use log::info;
+use serde_json::Value;
+use std::time::Duration;
+use teloxide::prelude::{ChatId, Requester};
+use teloxide::types::MessageId;
+use teloxide::Bot;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+/// Telegram limits how often a message can be edited, the events in between are shown together.
+const EDIT_INTERVAL: Duration = Duration::from_secs(3);
+
+/// How far the podcast got, as the progress message shows it.
+#[derive(Clone, Default)]
+struct Counts {
+    stage: Option<Stage>,
+    /// `None` until the sources are picked.
+    sources: Option<usize>,
+    read: usize,
+    failed: usize,
+    kept: usize,
+    /// Sources with anything worth the podcast, the ones to be summarized.
+    with_news: usize,
+    summarized: usize,
+}
+
+impl Counts {
+    fn apply(&mut self, event: ProgressEvent) {
+        match event {
+            ProgressEvent::Stage(stage) => self.stage = self.stage.max(Some(stage)),
+            ProgressEvent::SourcesFound(count) => self.sources = Some(count),
+            ProgressEvent::SourceRead { kept } => {
+                self.read += 1;
+                self.kept += kept;
+                if kept > 0 {
+                    self.with_news += 1;
+                }
+            }
+            ProgressEvent::SourceFailed => self.failed += 1,
+            ProgressEvent::SourceSummarized => self.summarized += 1,
+        }
+    }
+
+    fn render(&self, texts: &Value) -> String {
+        let text = |key: &str| texts[key].as_str().unwrap_or("Default message").to_string();
+
+        let Some(sources) = self.sources else {
+            return text("starting");
+        };
+
+        let mut lines = vec![
+            text("sources")
+                .replace("{read}", &(self.read + self.failed).to_string())
+                .replace("{total}", &sources.to_string()),
+            text("messages").replace("{kept}", &self.kept.to_string()),
+        ];
+        if self.stage >= Some(Stage::Summarizing) {
+            lines.push(
+                text("summarizing")
+                    .replace("{done}", &self.summarized.to_string())
+                    .replace("{total}", &self.with_news.to_string()),
+            );
+        }
+        if self.stage >= Some(Stage::Speaking) {
+            lines.push(text("speaking"));
+        }
+        lines.join("\n")
+    }
+}
+
+/// A message the user watches while the podcast is made, edited as the pipeline advances. The
+/// events are passed on to `inner` as well.
+pub(crate) struct ProgressMessage<'a> {
+    bot: Bot,
+    chat_id: ChatId,
+    message_id: MessageId,
+    texts: Value,
+    counts: watch::Sender<Counts>,
+    editor: JoinHandle<()>,
+    inner: &'a dyn Progress,
+    /// Set once the message is removed or tells about a failure, see `Drop`.
+    settled: bool,
+}
+
+impl<'a> ProgressMessage<'a> {
+    pub(crate) async fn send(
+        bot: Bot,
+        chat_id: ChatId,
+        localization: &Value,
+        inner: &'a dyn Progress,
+    ) -> anyhow::Result<Self> {
+        let texts = localization["progress_message"].clone();
+        let counts = Counts::default();
+        let mut shown = counts.render(&texts);
+        let message = bot.send_message(chat_id, shown.clone()).await?;
+        let (sender, mut receiver) = watch::channel(counts);
+
+        let editor = tokio::spawn({
+            let bot = bot.clone();
+            let texts = texts.clone();
+            let message_id = message.id;
+            async move {
+                while receiver.changed().await.is_ok() {
+                    let text = receiver.borrow_and_update().render(&texts);
+                    if text != shown {
+                        if let Err(e) = bot.edit_message_text(chat_id, message_id, &text).await {
+                            info!("Failed to update the progress of {}: {:?}", chat_id, e);
+                        }
+                        shown = text;
+                    }
+                    sleep(EDIT_INTERVAL).await;
+                }
+            }
+        });
+
+        Ok(Self {
+            bot,
+            chat_id,
+            message_id: message.id,
+            texts,
+            counts: sender,
+            editor,
+            inner,
+            settled: false,
+        })
+    }
+
+    /// The podcast is sent, the progress has nothing more to tell.
+    pub(crate) async fn remove(mut self) {
+        self.settled = true;
+        self.editor.abort();
+        if let Err(e) = self.bot.delete_message(self.chat_id, self.message_id).await {
+            info!("Failed to remove the progress of {}: {:?}", self.chat_id, e);
+        }
+    }
+
+    /// Leaves a word about the failure in place of the progress.
+    pub(crate) async fn fail(mut self) {
+        self.settled = true;
+        self.editor.abort();
+        let text = self.texts["failed"].as_str().unwrap_or("Default message");
+        show(&self.bot, self.chat_id, self.message_id, text).await;
+    }
+}
+
+async fn show(bot: &Bot, chat_id: ChatId, message_id: MessageId, text: &str) {
+    if let Err(e) = bot.edit_message_text(chat_id, message_id, text).await {
+        info!("Failed to update the progress of {}: {:?}", chat_id, e);
+    }
+}
+
+impl Progress for ProgressMessage<'_> {
+    fn report(&self, event: ProgressEvent) {
+        self.inner.report(event);
+        self.counts.send_modify(|counts| counts.apply(event));
+    }
+}
+
+impl Drop for ProgressMessage<'_> {
+    fn drop(&mut self) {
+        self.editor.abort();
+        if self.settled {
+            return;
+        }
+
+        // Only a job aborted by /cancel drops the message unsettled, it must not stay frozen
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let bot = self.bot.clone();
+        let (chat_id, message_id) = (self.chat_id, self.message_id);
+        let text = self.texts["cancelled"]
+            .as_str()
+            .unwrap_or("Default message")
+            .to_string();
+        runtime.spawn(async move { show(&bot, chat_id, message_id, &text).await });
+    }
+}
@@ -0,0 +1,346 @@
+use crate::client_pool::ClientPool;
+use crate::common_utils::{handle_getnews_cmd, load_localization, positive_from_env};
+use crate::llm::LlmProvider;
+use crate::news_block_creation_utils::Lookback;
+use crate::pipeline::{Progress, ProgressEvent, Stage};
+use crate::speech::SpeechSynthesizer;
+use crate::storage::Repository;
+use chrono_tz::Tz;
+use log::info;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use teloxide::prelude::{ChatId, Requester};
+use teloxide::Bot;
+use tokio::sync::{watch, Semaphore};
+use tokio::task::JoinHandle;
+
+/// Podcasts made at once for all users together, the other jobs wait in line.
+const DEFAULT_PODCAST_WORKERS: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JobStatus {
+    Queued,
+    Running(Stage),
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running(stage) => stage.as_str(),
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+
+    pub(crate) fn from_str(value: &str) -> Option<Self> {
+        match value {
+            "queued" => Some(JobStatus::Queued),
+            "done" => Some(JobStatus::Done),
+            "failed" => Some(JobStatus::Failed),
+            "cancelled" => Some(JobStatus::Cancelled),
+            stage => Stage::from_str(stage).map(JobStatus::Running),
+        }
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            JobStatus::Done | JobStatus::Failed | JobStatus::Cancelled
+        )
+    }
+}
+
+/// A podcast ordered with /getnews or by the schedule.
+#[derive(Clone, Debug)]
+pub struct PodcastJob {
+    pub id: i64,
+    pub chat_id: ChatId,
+    pub language_code: String,
+    pub time_zone: Tz,
+    pub lookback: Lookback,
+    pub status: JobStatus,
+}
+
+/// A job in the queue as its chat sees it.
+pub(crate) struct QueuedJob {
+    pub id: i64,
+    pub status: JobStatus,
+    /// Place in line among the waiting jobs, starting from 1; 0 once the job is running.
+    pub position: usize,
+    /// Changes as the job advances, the last value is the one it finished with.
+    pub updates: watch::Receiver<JobStatus>,
+}
+
+pub(crate) enum Enqueued {
+    Added(QueuedJob),
+    /// The chat has a podcast on its way already, a second one isn't queued.
+    AlreadyQueued(QueuedJob),
+}
+
+struct ActiveJob {
+    job: PodcastJob,
+    status: watch::Sender<JobStatus>,
+    handle: Option<JoinHandle<()>>,
+}
+
+/// Makes the podcasts one job after another, `PODCAST_WORKERS` of them at once. Every job is
+/// persisted, so what was queued when the bot stopped is made after a restart, and the chats of
+/// jobs cut short are told about it.
+pub(crate) struct PodcastQueue {
+    bot: Bot,
+    clients: Arc<ClientPool>,
+    storage: Arc<dyn Repository>,
+    llm: Arc<dyn LlmProvider>,
+    speech: Arc<dyn SpeechSynthesizer>,
+    /// Fair, so the jobs start in the order they were queued.
+    workers: Semaphore,
+    jobs: Mutex<HashMap<i64, ActiveJob>>,
+}
+
+impl PodcastQueue {
+    pub(crate) fn new(
+        bot: Bot,
+        clients: Arc<ClientPool>,
+        storage: Arc<dyn Repository>,
+        llm: Arc<dyn LlmProvider>,
+        speech: Arc<dyn SpeechSynthesizer>,
+    ) -> Self {
+        let workers = positive_from_env("PODCAST_WORKERS", DEFAULT_PODCAST_WORKERS);
+        info!("Podcast queue: {} podcasts at once", workers);
+
+        Self {
+            bot,
+            clients,
+            storage,
+            llm,
+            speech,
+            workers: Semaphore::new(workers),
+            jobs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes the jobs left over by the previous run, called once at startup: waiting ones are
+    /// queued again, running ones are marked failed and their chats are told.
+    pub(crate) async fn restore(self: &Arc<Self>) -> anyhow::Result<()> {
+        let jobs = self.storage.load_unfinished_jobs()?;
+        info!("Podcast queue: restoring {} jobs", jobs.len());
+
+        for job in jobs {
+            if job.status == JobStatus::Queued {
+                self.start(&mut self.jobs(), job);
+                continue;
+            }
+
+            info!("Podcast queue: job #{} was cut short by a restart", job.id);
+            self.storage
+                .save_job_status(job.id, JobStatus::Failed, Some("Interrupted by a restart"))?;
+
+            let message = load_localization(&job.language_code)["podcast_queue"]["interrupted"]
+                .as_str()
+                .unwrap_or("Default message")
+                .replace("{id}", &job.id.to_string());
+            if let Err(e) = self.bot.send_message(job.chat_id, message).await {
+                info!("Failed to tell {} about job #{}: {:?}", job.chat_id, job.id, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues a podcast for the chat, unless it has one on its way already.
+    pub(crate) fn enqueue(
+        self: &Arc<Self>,
+        chat_id: ChatId,
+        language_code: String,
+        time_zone: Tz,
+        lookback: Lookback,
+    ) -> anyhow::Result<Enqueued> {
+        // Checked and registered under one lock, two commands at once can't queue two podcasts
+        let mut jobs = self.jobs();
+        if let Some(active) = active_job_of(&jobs, chat_id) {
+            return Ok(Enqueued::AlreadyQueued(queued_job(&jobs, active)));
+        }
+
+        let mut job = PodcastJob {
+            id: 0,
+            chat_id,
+            language_code,
+            time_zone,
+            lookback,
+            status: JobStatus::Queued,
+        };
+        job.id = self.storage.insert_job(&job)?;
+        info!("Podcast queue: job #{} queued for {}", job.id, chat_id);
+
+        Ok(Enqueued::Added(self.start(&mut jobs, job)))
+    }
+
+    /// The podcast the chat has on its way, if any.
+    pub(crate) fn job_of(&self, chat_id: ChatId) -> Option<QueuedJob> {
+        let jobs = self.jobs();
+        let active = active_job_of(&jobs, chat_id)?;
+        Some(queued_job(&jobs, active))
+    }
+
+    /// Stops the podcast the chat has on its way, returns its id or `None` if there was none.
+    pub(crate) fn cancel(&self, chat_id: ChatId) -> anyhow::Result<Option<i64>> {
+        let active = {
+            let mut jobs = self.jobs();
+            let id = active_job_of(&jobs, chat_id).map(|active| active.job.id);
+            id.and_then(|id| jobs.remove(&id))
+        };
+        let Some(active) = active else {
+            return Ok(None);
+        };
+
+        if let Some(handle) = &active.handle {
+            handle.abort();
+        }
+        active.status.send_replace(JobStatus::Cancelled);
+        self.storage
+            .save_job_status(active.job.id, JobStatus::Cancelled, None)?;
+        info!("Podcast queue: job #{} cancelled", active.job.id);
+
+        Ok(Some(active.job.id))
+    }
+
+    /// Registers the job in `jobs`, the locked map, and spawns its task.
+    fn start(
+        self: &Arc<Self>,
+        jobs: &mut HashMap<i64, ActiveJob>,
+        job: PodcastJob,
+    ) -> QueuedJob {
+        let (status, _) = watch::channel(JobStatus::Queued);
+        let id = job.id;
+
+        // The job is registered before its task can report anything
+        jobs.insert(
+            id,
+            ActiveJob {
+                job: job.clone(),
+                status,
+                handle: None,
+            },
+        );
+
+        let queue = self.clone();
+        let handle = tokio::spawn(async move {
+            let Ok(_worker) = queue.workers.acquire().await else {
+                return;
+            };
+
+            let progress = JobProgress { queue: &queue, id };
+            progress.report(ProgressEvent::Stage(Stage::Fetching));
+
+            let result = handle_getnews_cmd(
+                queue.bot.clone(),
+                job.chat_id,
+                &queue.clients,
+                queue.llm.as_ref(),
+                queue.speech.as_ref(),
+                queue.storage.as_ref(),
+                &job.language_code,
+                job.time_zone,
+                job.lookback,
+                &progress,
+            )
+            .await;
+
+            match result {
+                Ok(()) => queue.finish(id, JobStatus::Done, None),
+                Err(e) => {
+                    eprintln!("Error in podcast job #{}: {:?}", id, e);
+                    queue.finish(id, JobStatus::Failed, Some(&format!("{:?}", e)));
+                }
+            }
+        });
+
+        let active = jobs.get_mut(&id).expect("the job was registered above");
+        active.handle = Some(handle);
+        queued_job(jobs, &jobs[&id])
+    }
+
+    /// Moves a running job on to `stage`; stages reported out of order don't move it back.
+    fn advance(&self, id: i64, stage: Stage) {
+        let jobs = self.jobs();
+        let Some(active) = jobs.get(&id) else {
+            return;
+        };
+
+        let advanced = active.status.send_if_modified(|status| match status {
+            JobStatus::Running(current) if *current >= stage => false,
+            _ => {
+                *status = JobStatus::Running(stage);
+                true
+            }
+        });
+        if advanced {
+            if let Err(e) = self
+                .storage
+                .save_job_status(id, JobStatus::Running(stage), None)
+            {
+                info!("Failed to save the status of job #{}: {:?}", id, e);
+            }
+        }
+    }
+
+    fn finish(&self, id: i64, status: JobStatus, error: Option<&str>) {
+        // A cancelled job is gone from the map already and keeps its status
+        let Some(active) = self.jobs().remove(&id) else {
+            return;
+        };
+
+        active.status.send_replace(status);
+        if let Err(e) = self.storage.save_job_status(id, status, error) {
+            info!("Failed to save the status of job #{}: {:?}", id, e);
+        }
+        info!("Podcast queue: job #{} is {}", id, status.as_str());
+    }
+
+    fn jobs(&self) -> std::sync::MutexGuard<'_, HashMap<i64, ActiveJob>> {
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn active_job_of(jobs: &HashMap<i64, ActiveJob>, chat_id: ChatId) -> Option<&ActiveJob> {
+    jobs.values().find(|active| active.job.chat_id == chat_id)
+}
+
+fn queued_job(jobs: &HashMap<i64, ActiveJob>, active: &ActiveJob) -> QueuedJob {
+    let status = *active.status.borrow();
+
+    let position = if status == JobStatus::Queued {
+        jobs.values()
+            .filter(|other| {
+                other.job.id <= active.job.id && *other.status.borrow() == JobStatus::Queued
+            })
+            .count()
+    } else {
+        0
+    };
+
+    QueuedJob {
+        id: active.job.id,
+        status,
+        position,
+        updates: active.status.subscribe(),
+    }
+}
+
+/// Reports the stages of one job to the queue.
+struct JobProgress<'a> {
+    queue: &'a PodcastQueue,
+    id: i64,
+}
+
+impl Progress for JobProgress<'_> {
+    fn report(&self, event: ProgressEvent) {
+        if let ProgressEvent::Stage(stage) = event {
+            self.queue.advance(self.id, stage);
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use crate::llm::{complete_structured, LlmProvider, StructuredOutput};
+use anyhow::Result;
+use log::info;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+
+/// Messages judged in one request; the answer stays short while the call count drops a lot.
+const BATCH_SIZE: usize = 25;
+/// Long posts are judged by their beginning.
+const MAX_MESSAGE_CHARS: usize = 1500;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Verdict {
+    Ok,
+    Skip,
+}
+
+fn verdict_schema() -> Value {
+    json!({ "type": "string", "enum": ["ok", "skip"] })
+}
+
+#[derive(Debug, Deserialize)]
+struct MessageVerdict {
+    id: i32,
+    verdict: Verdict,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct BatchVerdicts {
+    verdicts: Vec<MessageVerdict>,
+}
+
+impl StructuredOutput for BatchVerdicts {
+    const NAME: &'static str = "message_verdicts";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "verdicts": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "id": { "type": "integer" },
+                            "verdict": verdict_schema(),
+                        },
+                        "required": ["id", "verdict"],
+                        "additionalProperties": false,
+                    },
+                },
+            },
+            "required": ["verdicts"],
+            "additionalProperties": false,
+        })
+    }
+
+    /// Every message of the request is worth the podcast.
+    fn mock_example(request: &str) -> Value {
+        let messages: Vec<Value> = serde_json::from_str(request).unwrap_or_default();
+        let verdicts: Vec<Value> = messages
+            .iter()
+            .map(|message| json!({ "id": message["id"], "verdict": "ok" }))
+            .collect();
+        json!({ "verdicts": verdicts })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct SingleVerdict {
+    verdict: Verdict,
+}
+
+impl StructuredOutput for SingleVerdict {
+    const NAME: &'static str = "message_verdict";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": { "verdict": verdict_schema() },
+            "required": ["verdict"],
+            "additionalProperties": false,
+        })
+    }
+}
+
+/// Decides which channel messages deserve a place in the podcast, `true` for the ones to keep.
+/// Messages are sent to the model in batches with their ids and it answers with a JSON verdict
+/// list; the messages of a batch it didn't answer for properly are then judged one by one.
+pub(crate) async fn classify_messages(
+    llm: &dyn LlmProvider,
+    messages: &[(i32, String)],
+) -> Result<Vec<bool>> {
+    let batch_role = fs::read_to_string("common_res/system_role_7.txt")
+        .map_err(|e| format!("Failed to read 'system role': {}", e))
+        .unwrap();
+
+    let single_role = fs::read_to_string("common_res/system_role_4.txt")
+        .map_err(|e| format!("Failed to read 'system role': {}", e))
+        .unwrap();
+
+    let mut keep = Vec::with_capacity(messages.len());
+    for batch in messages.chunks(BATCH_SIZE) {
+        let request = Value::Array(
+            batch
+                .iter()
+                .map(|(id, text)| {
+                    let text: String = text.chars().take(MAX_MESSAGE_CHARS).collect();
+                    json!({ "id": id, "text": text })
+                })
+                .collect(),
+        )
+        .to_string();
+
+        let answer = complete_structured::<BatchVerdicts>(llm, &batch_role, &request);
+        let verdicts: HashMap<i32, Verdict> = match answer.await {
+            Ok(answer) => answer
+                .verdicts
+                .into_iter()
+                .map(|verdict| (verdict.id, verdict.verdict))
+                .collect(),
+            Err(e) => {
+                info!("Batch classification failed: {}", e);
+                HashMap::new()
+            }
+        };
+        info!(
+            "Classified {} messages in one request, {} verdicts received",
+            batch.len(),
+            verdicts.len()
+        );
+
+        for (id, text) in batch {
+            let verdict = match verdicts.get(id) {
+                Some(verdict) => *verdict,
+                None => {
+                    info!("No verdict for message {}, checking it on its own", id);
+                    match complete_structured::<SingleVerdict>(llm, &single_role, text).await {
+                        Ok(answer) => answer.verdict,
+                        // Better an extra update in the podcast than a lost one
+                        Err(e) => {
+                            info!("Message {} is kept unchecked: {}", id, e);
+                            Verdict::Ok
+                        }
+                    }
+                }
+            };
+            keep.push(verdict == Verdict::Ok);
+        }
+    }
+    Ok(keep)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::MockProvider;
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+
+    /// Remembers the names of the schemas asked for.
+    struct Recorded {
+        inner: MockProvider,
+        names: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl LlmProvider for Recorded {
+        async fn complete(&self, system_role: &str, request: &str) -> Result<String> {
+            self.inner.complete(system_role, request).await
+        }
+
+        async fn complete_json(
+            &self,
+            system_role: &str,
+            request: &str,
+            name: &str,
+            schema: &Value,
+        ) -> Result<String> {
+            self.names.lock().unwrap().push(name.to_string());
+            self.inner
+                .complete_json(system_role, request, name, schema)
+                .await
+        }
+    }
+
+    #[tokio::test]
+    async fn classify_messages_runs_on_the_mock_provider() {
+        let llm = Recorded {
+            inner: MockProvider::new(None),
+            names: Mutex::new(Vec::new()),
+        };
+        // More than a batch, the second one is short
+        let messages: Vec<(i32, String)> = (1..=BATCH_SIZE as i32 + 3)
+            .map(|id| (id, format!("Новость номер {}", id)))
+            .collect();
+
+        let keep = classify_messages(&llm, &messages).await.unwrap();
+
+        assert_eq!(keep, vec![true; messages.len()]);
+        // Both batches are answered properly, no message is checked on its own
+        assert_eq!(
+            *llm.names.lock().unwrap(),
+            [BatchVerdicts::NAME, BatchVerdicts::NAME]
+        );
+    }
+}
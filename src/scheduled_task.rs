@@ -1,48 +1,699 @@
-use crate::handle_getnews_cmd;
-use chrono::{DateTime, Datelike, FixedOffset, TimeZone, Utc};
+use crate::news_block_creation_utils::{Lookback, DEFAULT_LOOKBACK, RECAP_LOOKBACK};
+use crate::queue::{Enqueued, JobStatus, PodcastQueue};
+use crate::storage::Repository;
+use chrono::{
+    DateTime, Datelike, Duration, LocalResult, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday,
+};
+use chrono_tz::Tz;
 use log::info;
-use teloxide::prelude::Message;
-use teloxide::Bot;
-use tokio::time::{self, Duration as TokioDuration, Instant};
-
-pub(crate) async fn schedule_daily_getnews_task(bot: Bot, msg: Message, language_code: String) {
-    let offset = FixedOffset::east_opt(3 * 3600).unwrap();
-    let now: DateTime<FixedOffset> = Utc::now().with_timezone(&offset);
-    let podcast_time = offset
-        .with_ymd_and_hms(now.year(), now.month(), now.day(), 9, 00, 00)
-        .unwrap();
-
-    let duration_until_podcast_time = if now > podcast_time {
-        podcast_time + chrono::Duration::days(1) - now
-    } else {
-        podcast_time - now
-    };
-
-    let start_at =
-        Instant::now() + TokioDuration::from_secs(duration_until_podcast_time.num_seconds() as u64);
-    let mut interval = time::interval_at(start_at, TokioDuration::from_secs(24 * 60 * 60));
-
-    info!("Current time (UTC+3): {}", now);
-    info!("Scheduled podcast time (UTC+3): {}", podcast_time);
-    info!(
-        "Duration until podcast time: {}",
-        duration_until_podcast_time
-    );
-    let hours = duration_until_podcast_time.num_hours();
-    let minutes = duration_until_podcast_time.num_minutes() % 60;
-    let seconds = duration_until_podcast_time.num_seconds() % 60;
-    info!(
-        "Duration until podcast time: {} hours, {} minutes, {} seconds",
-        hours, minutes, seconds
-    );
-
-    tokio::spawn(async move {
-        loop {
-            interval.tick().await;
-
-            if let Err(e) = handle_getnews_cmd(bot.clone(), msg.clone(), &language_code).await {
-                eprintln!("Error in 'getnews' daily task: {:?}", e);
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use teloxide::prelude::ChatId;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+/// Used until the user picks their own with /schedule, it's the UTC+3 the bot always worked in.
+pub(crate) const DEFAULT_TIME_ZONE: Tz = chrono_tz::Europe::Moscow;
+
+const ALL_DAYS: [Weekday; 7] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+    Weekday::Sat,
+    Weekday::Sun,
+];
+
+/// One line of a user's schedule: the week days, the delivery times on those days and whether
+/// the podcast is a weekly recap rather than a regular news block.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeliveryRule {
+    pub days: Vec<Weekday>,
+    pub times: Vec<NaiveTime>,
+    pub recap: bool,
+}
+
+impl Default for DeliveryRule {
+    fn default() -> Self {
+        Self {
+            days: ALL_DAYS.to_vec(),
+            times: vec![NaiveTime::from_hms_opt(9, 0, 0).unwrap()],
+            recap: false,
+        }
+    }
+}
+
+impl DeliveryRule {
+    pub(crate) fn lookback(&self) -> Lookback {
+        if self.recap {
+            RECAP_LOOKBACK
+        } else {
+            DEFAULT_LOOKBACK
+        }
+    }
+}
+
+/// Formats the rule in the same syntax /schedule accepts, e.g. `weekdays 08:30 18:00`.
+impl fmt::Display for DeliveryRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let days = if self.days.len() == 7 {
+            "daily".to_string()
+        } else if self.days == ALL_DAYS[..5] {
+            "weekdays".to_string()
+        } else if self.days == ALL_DAYS[5..] {
+            "weekends".to_string()
+        } else {
+            self.days
+                .iter()
+                .map(|day| day.to_string().to_lowercase())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+
+        write!(f, "{}", days)?;
+        for time in &self.times {
+            write!(f, " {}", time.format("%H:%M"))?;
+        }
+        if self.recap {
+            write!(f, " recap")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeliverySchedule {
+    pub chat_id: ChatId,
+    pub language_code: String,
+    pub rules: Vec<DeliveryRule>,
+    pub time_zone: Tz,
+    pub paused_until: Option<DateTime<Utc>>,
+}
+
+impl DeliverySchedule {
+    /// The closest delivery after `now`, deliveries falling into a pause are skipped.
+    pub(crate) fn next_run(&self, now: DateTime<Utc>) -> Option<(DateTime<Utc>, DeliveryRule)> {
+        let from = self.paused_until.filter(|until| *until > now).unwrap_or(now);
+        next_fire_time(from, &self.rules, self.time_zone)
+    }
+}
+
+/// Tells the scheduler what time it is.
+pub(crate) trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Takes the podcasts the schedules are due for.
+pub(crate) trait PodcastOrders: Send + Sync {
+    fn order(&self, schedule: &DeliverySchedule, lookback: Lookback) -> anyhow::Result<Enqueued>;
+}
+
+impl PodcastOrders for Arc<PodcastQueue> {
+    fn order(&self, schedule: &DeliverySchedule, lookback: Lookback) -> anyhow::Result<Enqueued> {
+        self.enqueue(
+            schedule.chat_id,
+            schedule.language_code.clone(),
+            schedule.time_zone,
+            lookback,
+        )
+    }
+}
+
+struct ScheduledJob {
+    schedule: DeliverySchedule,
+    handle: JoinHandle<()>,
+}
+
+/// Owns the podcast delivery jobs; every schedule is persisted, so jobs are re-armed after a restart.
+/// There is at most one job per chat, the registry is the only place where jobs are spawned.
+pub(crate) struct Scheduler {
+    storage: Arc<dyn Repository>,
+    orders: Arc<dyn PodcastOrders>,
+    clock: Arc<dyn Clock>,
+    jobs: Mutex<HashMap<ChatId, ScheduledJob>>,
+    /// How many times each chat was stopped, only read and written under the `jobs` lock.
+    stops: Mutex<HashMap<ChatId, u64>>,
+}
+
+impl Scheduler {
+    pub(crate) fn new(
+        storage: Arc<dyn Repository>,
+        orders: Arc<dyn PodcastOrders>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            storage,
+            orders,
+            clock,
+            jobs: Mutex::new(HashMap::new()),
+            stops: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Re-arms every schedule found in the storage, called once at startup.
+    pub(crate) fn restore(&self) -> anyhow::Result<()> {
+        let schedules = self.storage.load_schedules()?;
+        info!("Scheduler: restoring {} getnews tasks", schedules.len());
+
+        let mut jobs = self.jobs();
+        for schedule in schedules {
+            self.arm(&mut jobs, schedule);
+        }
+        Ok(())
+    }
+
+    /// Persists and arms the schedule, an identical active job is kept, a different one is replaced.
+    pub(crate) fn schedule(&self, schedule: DeliverySchedule) -> anyhow::Result<()> {
+        // Held until the job is armed, calls at the same time can't leave two jobs for one chat
+        let mut jobs = self.jobs();
+        let already_active = jobs
+            .get(&schedule.chat_id)
+            .is_some_and(|job| job.schedule == schedule);
+        if already_active {
+            info!("Scheduler: {} already has the same getnews task", schedule.chat_id);
+            return Ok(());
+        }
+
+        self.storage.save_schedule(&schedule)?;
+        self.arm(&mut jobs, schedule);
+        info!("Scheduler: {} active getnews tasks", jobs.len());
+        Ok(())
+    }
+
+    pub(crate) fn active_job(&self, chat_id: ChatId) -> Option<DeliverySchedule> {
+        self.jobs().get(&chat_id).map(|job| job.schedule.clone())
+    }
+
+    /// Skips the deliveries of the next `days` days, returns when the pause ends
+    /// or `None` if the chat has no scheduled podcasts.
+    pub(crate) fn pause(
+        &self,
+        chat_id: ChatId,
+        days: u32,
+    ) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let Some(mut schedule) = self.active_job(chat_id) else {
+            return Ok(None);
+        };
+
+        let paused_until = self.clock.now() + Duration::days(days as i64);
+        schedule.paused_until = Some(paused_until);
+        self.schedule(schedule)?;
+        info!("Scheduler: getnews task of {} paused until {}", chat_id, paused_until);
+
+        Ok(Some(paused_until))
+    }
+
+    /// Lifts a pause, returns `false` if the chat has no scheduled podcasts.
+    pub(crate) fn resume(&self, chat_id: ChatId) -> anyhow::Result<bool> {
+        let Some(mut schedule) = self.active_job(chat_id) else {
+            return Ok(false);
+        };
+
+        schedule.paused_until = None;
+        self.schedule(schedule)?;
+        info!("Scheduler: getnews task of {} resumed", chat_id);
+
+        Ok(true)
+    }
+
+    /// Cancels the job and forgets the schedule, returns `false` if there was nothing to stop.
+    pub(crate) fn stop(&self, chat_id: ChatId) -> anyhow::Result<bool> {
+        let mut jobs = self.jobs();
+        let was_active = cancel_job(&mut jobs, chat_id);
+        *self.stops().entry(chat_id).or_default() += 1;
+        self.storage.delete_schedule(chat_id)?;
+        Ok(was_active)
+    }
+
+    /// Subscribes the chat to `schedule` once its /getnews podcast is done. The schedule the chat
+    /// has by then is kept as it is, and a chat stopped while the podcast was made stays stopped.
+    pub(crate) fn subscribe_when_done(
+        self: Arc<Self>,
+        mut updates: watch::Receiver<JobStatus>,
+        schedule: DeliverySchedule,
+    ) -> JoinHandle<()> {
+        let stops = self.stop_count(schedule.chat_id);
+
+        // Making the podcast takes minutes, the caller doesn't wait for it
+        tokio::spawn(async move {
+            let finished = updates
+                .wait_for(|status| status.is_finished())
+                .await
+                .map(|status| *status);
+            if finished.ok() != Some(JobStatus::Done) {
+                return;
+            }
+
+            match self.subscribe(schedule, stops) {
+                Ok(true) => info!("Scheduler: getnews task armed after a manual podcast"),
+                Ok(false) => {}
+                Err(e) => eprintln!("Error in 'getnews' task: {:?}", e),
+            }
+        })
+    }
+
+    /// Arms `schedule` unless the chat already has one or was stopped after `stops` was counted.
+    fn subscribe(&self, schedule: DeliverySchedule, stops: u64) -> anyhow::Result<bool> {
+        let mut jobs = self.jobs();
+        let chat_id = schedule.chat_id;
+        if self.stop_count(chat_id) != stops {
+            info!("Scheduler: {} stopped while its podcast was made, not subscribing", chat_id);
+            return Ok(false);
+        }
+        if jobs.contains_key(&chat_id) {
+            return Ok(false);
+        }
+
+        self.storage.save_schedule(&schedule)?;
+        self.arm(&mut jobs, schedule);
+        Ok(true)
+    }
+
+    fn stop_count(&self, chat_id: ChatId) -> u64 {
+        self.stops().get(&chat_id).copied().unwrap_or(0)
+    }
+
+    fn stops(&self) -> std::sync::MutexGuard<'_, HashMap<ChatId, u64>> {
+        self.stops.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn jobs(&self) -> std::sync::MutexGuard<'_, HashMap<ChatId, ScheduledJob>> {
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Spawns the job of the schedule into `jobs`, the locked map, aborting the one it replaces.
+    fn arm(&self, jobs: &mut HashMap<ChatId, ScheduledJob>, schedule: DeliverySchedule) {
+        let orders = self.orders.clone();
+        let clock = self.clock.clone();
+        let job_schedule = schedule.clone();
+
+        let handle = tokio::spawn(async move {
+            let schedule = job_schedule;
+            loop {
+                let now = clock.now();
+                let Some((fire_at, rule)) = schedule.next_run(now) else {
+                    info!("Scheduler: {} has no delivery rules", schedule.chat_id);
+                    return;
+                };
+                let duration_until_podcast_time = fire_at - now;
+
+                info!(
+                    "Scheduler: next podcast for {} at {} (in {} hours, {} minutes)",
+                    schedule.chat_id,
+                    fire_at,
+                    duration_until_podcast_time.num_hours(),
+                    duration_until_podcast_time.num_minutes() % 60
+                );
+
+                sleep(duration_until_podcast_time.to_std().unwrap_or_default()).await;
+
+                match orders.order(&schedule, rule.lookback()) {
+                    Ok(Enqueued::Added(_)) => {}
+                    Ok(Enqueued::AlreadyQueued(job)) => info!(
+                        "Scheduler: {} still waits for podcast #{}, skipping this one",
+                        schedule.chat_id, job.id
+                    ),
+                    Err(e) => eprintln!("Error in 'getnews' scheduled task: {:?}", e),
+                }
+            }
+        });
+
+        let chat_id = schedule.chat_id;
+        // A dropped handle would only detach the old job, it has to be aborted
+        if let Some(replaced) = jobs.insert(chat_id, ScheduledJob { schedule, handle }) {
+            replaced.handle.abort();
+            info!("Scheduler: getnews task of {} replaced", chat_id);
+        }
+    }
+}
+
+/// Aborts the job of the chat, returns `false` if there was nothing to cancel.
+fn cancel_job(jobs: &mut HashMap<ChatId, ScheduledJob>, chat_id: ChatId) -> bool {
+    match jobs.remove(&chat_id) {
+        Some(job) => {
+            job.handle.abort();
+            info!("Scheduler: getnews task of {} cancelled", chat_id);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Returns the closest delivery moment strictly after `now` together with the rule it comes from,
+/// the rule times are wall clock times in `time_zone`.
+pub(crate) fn next_fire_time(
+    now: DateTime<Utc>,
+    rules: &[DeliveryRule],
+    time_zone: Tz,
+) -> Option<(DateTime<Utc>, DeliveryRule)> {
+    let local_today = now.with_timezone(&time_zone).date_naive();
+
+    // A week ahead is enough to hit every week day once, the extra day covers the rest of today
+    (0..=7)
+        .filter_map(|days| local_today.checked_add_signed(Duration::days(days)))
+        .flat_map(|date| {
+            rules
+                .iter()
+                .filter(move |rule| rule.days.contains(&date.weekday()))
+                .flat_map(move |rule| {
+                    rule.times
+                        .iter()
+                        .map(move |time| (date.and_time(*time), rule))
+                })
+        })
+        .map(|(local, rule)| (resolve_local_time(time_zone, local), rule))
+        .filter(|(fire_at, _)| *fire_at > now)
+        .min_by_key(|(fire_at, _)| *fire_at)
+        .map(|(fire_at, rule)| (fire_at, rule.clone()))
+}
+
+/// A wall clock time may happen twice (DST ends) or never (DST starts): the first occurrence is
+/// taken in the former case, the time is moved forward by the size of the gap in the latter.
+fn resolve_local_time(time_zone: Tz, local: NaiveDateTime) -> DateTime<Utc> {
+    match time_zone.from_local_datetime(&local) {
+        LocalResult::Single(time) => time.with_timezone(&Utc),
+        LocalResult::Ambiguous(earliest, _) => earliest.with_timezone(&Utc),
+        LocalResult::None => {
+            let shifted = (1..=4)
+                .filter_map(|half_hours| {
+                    time_zone
+                        .from_local_datetime(&(local + Duration::minutes(30 * half_hours)))
+                        .earliest()
+                })
+                .next()
+                .expect("DST gap is shorter than two hours");
+            shifted.with_timezone(&Utc)
+        }
+    }
+}
+
+/// Parses the /schedule rule syntax: rules are separated by `;`, each one is an optional day spec
+/// (`daily`, `weekdays`, `weekends`, `mon-fri`, `sat,sun`, ...), one or more `HH:MM` times and an
+/// optional `recap` flag. An IANA time zone may appear anywhere, e.g.
+/// `weekdays 08:30 18:00; sun 10:00 recap; Europe/Berlin`. Returns the token it failed on.
+pub(crate) fn parse_schedule_args(args: &str) -> Result<(Vec<DeliveryRule>, Option<Tz>), String> {
+    let mut rules = Vec::new();
+    let mut time_zone = None;
+
+    for segment in args.split([';', '\n']).map(str::trim).filter(|s| !s.is_empty()) {
+        let mut days = Vec::new();
+        let mut times = Vec::new();
+        let mut recap = false;
+
+        for token in segment
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|t| !t.is_empty())
+        {
+            if let Ok(time) = NaiveTime::parse_from_str(token, "%H:%M") {
+                times.push(time);
+            } else if let Some(parsed_days) = parse_days(token) {
+                days.extend(parsed_days);
+            } else if token.eq_ignore_ascii_case("recap") {
+                recap = true;
+            } else if let Ok(tz) = token.parse::<Tz>() {
+                time_zone = Some(tz);
+            } else {
+                return Err(token.to_string());
+            }
+        }
+
+        if times.is_empty() {
+            // A segment holding nothing but the time zone is fine, anything else needs a time
+            if !days.is_empty() || recap {
+                return Err(segment.to_string());
             }
+            continue;
+        }
+
+        if days.is_empty() {
+            days = ALL_DAYS.to_vec();
+        }
+        days.sort_by_key(|day| day.num_days_from_monday());
+        days.dedup();
+        times.sort();
+        times.dedup();
+
+        rules.push(DeliveryRule { days, times, recap });
+    }
+
+    Ok((rules, time_zone))
+}
+
+fn parse_days(token: &str) -> Option<Vec<Weekday>> {
+    match token.to_lowercase().as_str() {
+        "daily" | "everyday" => return Some(ALL_DAYS.to_vec()),
+        "weekdays" => return Some(ALL_DAYS[..5].to_vec()),
+        "weekends" => return Some(ALL_DAYS[5..].to_vec()),
+        _ => {}
+    }
+
+    if let Some((from, to)) = token.split_once('-') {
+        let from = from.parse::<Weekday>().ok()?;
+        let to = to.parse::<Weekday>().ok()?;
+        let mut days = vec![from];
+        let mut day = from;
+        while day != to {
+            day = day.succ();
+            days.push(day);
+        }
+        return Some(days);
+    }
+
+    token.parse::<Weekday>().ok().map(|day| vec![day])
+}
+
+pub(crate) fn format_rules(rules: &[DeliveryRule]) -> String {
+    rules
+        .iter()
+        .map(|rule| rule.to_string())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::Stage;
+    use crate::queue::QueuedJob;
+    use crate::storage::SqliteRepository;
+    use tokio::time::{advance, Instant};
+
+    const CHAT: ChatId = ChatId(42);
+
+    /// Wall clock time that moves along with tokio's clock, so paused tests can drive it.
+    struct TokioClock {
+        start: DateTime<Utc>,
+        started: Instant,
+    }
+
+    impl TokioClock {
+        fn starting_at(start: DateTime<Utc>) -> Arc<Self> {
+            Arc::new(Self {
+                start,
+                started: Instant::now(),
+            })
         }
-    });
+    }
+
+    impl Clock for TokioClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.start + Duration::from_std(self.started.elapsed()).unwrap()
+        }
+    }
+
+    /// Remembers the chats podcasts were ordered for.
+    #[derive(Default)]
+    struct RecordedOrders {
+        chats: Mutex<Vec<ChatId>>,
+    }
+
+    impl RecordedOrders {
+        fn chats(&self) -> Vec<ChatId> {
+            self.chats.lock().unwrap().clone()
+        }
+    }
+
+    impl PodcastOrders for RecordedOrders {
+        fn order(&self, schedule: &DeliverySchedule, _: Lookback) -> anyhow::Result<Enqueued> {
+            let mut chats = self.chats.lock().unwrap();
+            chats.push(schedule.chat_id);
+            let (_, updates) = watch::channel(JobStatus::Queued);
+            Ok(Enqueued::Added(QueuedJob {
+                id: chats.len() as i64,
+                status: JobStatus::Queued,
+                position: 1,
+                updates,
+            }))
+        }
+    }
+
+    fn storage() -> Arc<dyn Repository> {
+        Arc::new(SqliteRepository::open(":memory:").unwrap())
+    }
+
+    /// 2024-01-01 was a Monday.
+    fn monday(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 1, hour, minute, 0).unwrap()
+    }
+
+    fn daily_at(hour: u32, minute: u32) -> DeliverySchedule {
+        DeliverySchedule {
+            chat_id: CHAT,
+            language_code: "en".to_string(),
+            rules: vec![DeliveryRule {
+                times: vec![NaiveTime::from_hms_opt(hour, minute, 0).unwrap()],
+                ..DeliveryRule::default()
+            }],
+            time_zone: chrono_tz::UTC,
+            paused_until: None,
+        }
+    }
+
+    /// Moves tokio's clock on and lets the jobs it woke up run.
+    async fn pass(duration: Duration) {
+        advance(duration.to_std().unwrap()).await;
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restored_schedule_fires_after_a_restart() {
+        let storage = storage();
+        let clock = TokioClock::starting_at(monday(8, 0));
+
+        // The first run saves the schedule and stops before the delivery is due
+        let before_restart = Scheduler::new(
+            storage.clone(),
+            Arc::new(RecordedOrders::default()),
+            clock.clone(),
+        );
+        before_restart.schedule(daily_at(9, 0)).unwrap();
+        cancel_job(&mut before_restart.jobs(), CHAT);
+
+        let orders = Arc::new(RecordedOrders::default());
+        let scheduler = Scheduler::new(storage, orders.clone(), clock);
+        scheduler.restore().unwrap();
+        pass(Duration::zero()).await;
+
+        pass(Duration::minutes(59)).await;
+        assert!(orders.chats().is_empty());
+
+        pass(Duration::minutes(2)).await;
+        assert_eq!(orders.chats(), vec![CHAT]);
+
+        pass(Duration::days(1)).await;
+        assert_eq!(orders.chats(), vec![CHAT, CHAT]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paused_schedule_fires_once_the_pause_is_over() {
+        let orders = Arc::new(RecordedOrders::default());
+        let scheduler = Scheduler::new(
+            storage(),
+            orders.clone(),
+            TokioClock::starting_at(monday(8, 0)),
+        );
+        scheduler.schedule(daily_at(9, 0)).unwrap();
+        // Until Wednesday 08:00, the deliveries of Monday and Tuesday are skipped
+        scheduler.pause(CHAT, 2).unwrap();
+        pass(Duration::zero()).await;
+
+        pass(Duration::hours(48)).await;
+        assert!(orders.chats().is_empty());
+
+        pass(Duration::hours(2)).await;
+        assert_eq!(orders.chats(), vec![CHAT]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scheduling_twice_leaves_one_armed_job() {
+        let orders = Arc::new(RecordedOrders::default());
+        let scheduler = Scheduler::new(
+            storage(),
+            orders.clone(),
+            TokioClock::starting_at(monday(8, 0)),
+        );
+        scheduler.schedule(daily_at(9, 0)).unwrap();
+        scheduler.schedule(daily_at(9, 30)).unwrap();
+        scheduler.schedule(daily_at(9, 30)).unwrap();
+        pass(Duration::zero()).await;
+
+        assert_eq!(scheduler.jobs().len(), 1);
+        assert_eq!(scheduler.active_job(CHAT), Some(daily_at(9, 30)));
+        // The replaced 09:00 job doesn't fire, the 09:30 one fires once
+        pass(Duration::minutes(61)).await;
+        assert!(orders.chats().is_empty());
+        pass(Duration::minutes(30)).await;
+        assert_eq!(orders.chats(), vec![CHAT]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stop_during_a_running_getnews_keeps_the_user_stopped() {
+        let storage = storage();
+        let orders = Arc::new(RecordedOrders::default());
+        let scheduler = Arc::new(Scheduler::new(
+            storage.clone(),
+            orders.clone(),
+            TokioClock::starting_at(monday(8, 0)),
+        ));
+        scheduler.schedule(daily_at(9, 0)).unwrap();
+
+        let (status, updates) = watch::channel(JobStatus::Running(Stage::Speaking));
+        let subscription = scheduler.clone().subscribe_when_done(updates, daily_at(9, 0));
+        scheduler.stop(CHAT).unwrap();
+        status.send(JobStatus::Done).unwrap();
+        subscription.await.unwrap();
+
+        pass(Duration::days(1)).await;
+        assert_eq!(scheduler.active_job(CHAT), None);
+        assert!(storage.load_schedules().unwrap().is_empty());
+        assert!(orders.chats().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn getnews_keeps_the_schedule_changed_while_it_ran() {
+        let scheduler = Arc::new(Scheduler::new(
+            storage(),
+            Arc::new(RecordedOrders::default()),
+            TokioClock::starting_at(monday(8, 0)),
+        ));
+
+        let (status, updates) = watch::channel(JobStatus::Queued);
+        let subscription = scheduler.clone().subscribe_when_done(updates, daily_at(9, 0));
+        scheduler.schedule(daily_at(18, 0)).unwrap();
+        scheduler.pause(CHAT, 2).unwrap();
+        status.send(JobStatus::Done).unwrap();
+        subscription.await.unwrap();
+
+        let schedule = scheduler.active_job(CHAT).unwrap();
+        assert_eq!(schedule.rules, daily_at(18, 0).rules);
+        assert!(schedule.paused_until.is_some());
+    }
+
+    #[test]
+    fn next_fire_time_moves_a_skipped_time_past_the_dst_gap() {
+        let berlin = chrono_tz::Europe::Berlin;
+        let rules = [DeliveryRule {
+            times: vec![NaiveTime::from_hms_opt(2, 30, 0).unwrap()],
+            ..DeliveryRule::default()
+        }];
+        // 02:30 doesn't exist in Berlin on 2024-03-31, the clocks jump from 02:00 to 03:00
+        let now = Utc.with_ymd_and_hms(2024, 3, 31, 0, 0, 0).unwrap();
+
+        let (fire_at, _) = next_fire_time(now, &rules, berlin).unwrap();
+
+        assert_eq!(fire_at, Utc.with_ymd_and_hms(2024, 3, 31, 1, 0, 0).unwrap());
+    }
 }
@@ -0,0 +1,305 @@
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use grammers_session::Session;
+use log::info;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Starts every encrypted session file, plain grammers sessions never start with it.
+const MAGIC: &[u8] = b"NWSESS01";
+const NONCE_LENGTH: usize = 24;
+
+/// Reads and writes the session files of the users, encrypted with XChaCha20-Poly1305. A session
+/// grants full access to the Telegram account, so it never touches the disk in plain form; the
+/// user id is authenticated along with it, a file copied over another user's doesn't load.
+pub(crate) struct SessionStore {
+    cipher: XChaCha20Poly1305,
+    /// Holds a `{user_id}.session` file per user.
+    dir: PathBuf,
+}
+
+impl SessionStore {
+    /// Takes the key from the env var `name`: 32 bytes in base64, e.g. `openssl rand -base64 32`.
+    pub(crate) fn from_env(name: &str, dir: impl Into<PathBuf>) -> Result<Self> {
+        let key = env::var(name).with_context(|| format!("{} must be set", name))?;
+        let key = BASE64
+            .decode(key.trim())
+            .with_context(|| format!("{} is not valid base64", name))?;
+        let cipher = XChaCha20Poly1305::new_from_slice(&key)
+            .map_err(|_| anyhow!("{} must be 32 bytes long, it's {}", name, key.len()))?;
+        Ok(Self {
+            cipher,
+            dir: dir.into(),
+        })
+    }
+
+    pub(crate) fn path(&self, user_id: u64) -> PathBuf {
+        self.dir.join(format!("{}.session", user_id))
+    }
+
+    /// The saved session of the user, a new one if there's none yet.
+    pub(crate) fn load(&self, user_id: u64) -> Result<Session> {
+        let path = self.path(user_id);
+        if !path.exists() {
+            return Ok(Session::new());
+        }
+
+        let data = fs::read(&path)?;
+        let Some(sealed) = data.strip_prefix(MAGIC) else {
+            bail!("{:?} isn't encrypted", path);
+        };
+        let plain = self
+            .open(sealed, user_id)
+            .with_context(|| format!("Failed to decrypt {:?}", path))?;
+        Session::load(&plain).map_err(|e| anyhow!("Failed to parse {:?}: {}", path, e))
+    }
+
+    pub(crate) fn save(&self, session: &Session, user_id: u64) -> Result<()> {
+        self.write(&self.path(user_id), &session.save(), user_id)
+    }
+
+    /// Encrypts the session files left in plain form by older versions, called once at startup.
+    pub(crate) fn encrypt_plain_files(&self) -> Result<()> {
+        let mut encrypted = 0;
+        for (path, user_id) in session_files(&self.dir)? {
+            let data = fs::read(&path)?;
+            if data.starts_with(MAGIC) {
+                continue;
+            }
+
+            // Parsed first, so that nothing but a real session gets sealed
+            let session =
+                Session::load(&data).map_err(|e| anyhow!("Failed to parse {:?}: {}", path, e))?;
+            self.write(&path, &session.save(), user_id)?;
+            encrypted += 1;
+        }
+
+        if encrypted > 0 {
+            info!("Session store: {} plain session files encrypted", encrypted);
+        }
+        Ok(())
+    }
+
+    /// Re-encrypts every session file sealed with `old` with this key. Files already on this key
+    /// are left as they are, so an interrupted rotation can simply be run again.
+    pub(crate) fn rotate_from(&self, old: &SessionStore) -> Result<usize> {
+        let mut rotated = 0;
+        for (path, user_id) in session_files(&self.dir)? {
+            let data = fs::read(&path)?;
+            let Some(sealed) = data.strip_prefix(MAGIC) else {
+                bail!("{:?} isn't encrypted, start the bot once to encrypt it", path);
+            };
+            if self.open(sealed, user_id).is_ok() {
+                continue;
+            }
+
+            let plain = old
+                .open(sealed, user_id)
+                .with_context(|| format!("Failed to decrypt {:?} with the old key", path))?;
+            self.write(&path, &plain, user_id)?;
+            rotated += 1;
+        }
+        Ok(rotated)
+    }
+
+    fn open(&self, sealed: &[u8], user_id: u64) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_LENGTH {
+            bail!("The file is truncated");
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+        let aad = associated_data(user_id);
+
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| anyhow!("Wrong key or a damaged file"))
+    }
+
+    /// Writes a temporary file first, a crash never leaves a half-written session behind.
+    fn write(&self, path: &Path, plain: &[u8], user_id: u64) -> Result<()> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = associated_data(user_id);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plain,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| anyhow!("Failed to encrypt the session of {}", user_id))?;
+
+        let mut data = Vec::with_capacity(MAGIC.len() + NONCE_LENGTH + ciphertext.len());
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+
+        fs::create_dir_all(&self.dir)?;
+        let temporary = path.with_extension("session.tmp");
+        fs::write(&temporary, data)?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+}
+
+fn associated_data(user_id: u64) -> Vec<u8> {
+    format!("news_wizard session {}", user_id).into_bytes()
+}
+
+/// Every `{user_id}.session` file in `dir` with its user id.
+fn session_files(dir: &Path) -> Result<Vec<(PathBuf, u64)>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() != Some("session".as_ref()) {
+            continue;
+        }
+        if let Some(user_id) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok())
+        {
+            files.push((path, user_id));
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: u64 = 7;
+    const SESSION: &[u8] = b"session data";
+
+    /// A store over its own empty directory in the system temp dir, removed when dropped.
+    struct TestStore {
+        store: SessionStore,
+    }
+
+    impl TestStore {
+        fn new(name: &str, key: u8) -> Self {
+            let dir = env::temp_dir().join(format!("news_wizard_{}_{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            Self {
+                store: with_key(key, dir),
+            }
+        }
+    }
+
+    impl Drop for TestStore {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.store.dir);
+        }
+    }
+
+    fn with_key(key: u8, dir: PathBuf) -> SessionStore {
+        SessionStore {
+            cipher: XChaCha20Poly1305::new(&[key; 32].into()),
+            dir,
+        }
+    }
+
+    fn seal(store: &SessionStore, user_id: u64) {
+        store.write(&store.path(user_id), SESSION, user_id).unwrap();
+    }
+
+    /// What the file of the user decrypts to with `store`.
+    fn read(store: &SessionStore, user_id: u64) -> Result<Vec<u8>> {
+        let data = fs::read(store.path(user_id))?;
+        let sealed = data.strip_prefix(MAGIC).context("no magic")?;
+        store.open(sealed, user_id)
+    }
+
+    #[test]
+    fn sealed_session_opens_to_the_same_bytes() {
+        let test = TestStore::new("round_trip", 1);
+        let store = &test.store;
+        seal(store, USER);
+
+        let data = fs::read(store.path(USER)).unwrap();
+        assert!(data.starts_with(MAGIC));
+        assert!(!data.windows(SESSION.len()).any(|bytes| bytes == SESSION));
+        assert_eq!(read(store, USER).unwrap(), SESSION);
+    }
+
+    #[test]
+    fn session_of_another_user_does_not_open() {
+        let test = TestStore::new("other_user", 1);
+        let store = &test.store;
+        seal(store, USER);
+        fs::copy(store.path(USER), store.path(USER + 1)).unwrap();
+
+        assert!(read(store, USER + 1).is_err());
+        assert!(store.load(USER + 1).is_err());
+    }
+
+    #[test]
+    fn rotating_twice_is_harmless() {
+        let test = TestStore::new("rotation", 2);
+        let store = &test.store;
+        let old = with_key(1, store.dir.clone());
+        seal(&old, USER);
+
+        assert_eq!(store.rotate_from(&old).unwrap(), 1);
+        assert_eq!(store.rotate_from(&old).unwrap(), 0);
+        assert_eq!(read(store, USER).unwrap(), SESSION);
+        assert!(read(&old, USER).is_err());
+    }
+
+    #[test]
+    fn plain_session_file_is_encrypted_in_place() {
+        let test = TestStore::new("migration", 1);
+        let store = &test.store;
+        let plain = Session::new().save();
+        fs::create_dir_all(&store.dir).unwrap();
+        fs::write(store.path(USER), &plain).unwrap();
+
+        store.encrypt_plain_files().unwrap();
+
+        assert!(fs::read(store.path(USER)).unwrap().starts_with(MAGIC));
+        assert_eq!(read(store, USER).unwrap(), plain);
+        assert!(store.load(USER).is_ok());
+        // Nothing is left to migrate on the next start
+        store.encrypt_plain_files().unwrap();
+        assert_eq!(read(store, USER).unwrap(), plain);
+    }
+
+    #[test]
+    fn damaged_files_are_errors() {
+        let test = TestStore::new("damaged", 1);
+        let store = &test.store;
+        seal(store, USER);
+        let data = fs::read(store.path(USER)).unwrap();
+
+        let cut_in_the_nonce = &data[..MAGIC.len() + NONCE_LENGTH / 2];
+        let cut_in_the_text = &data[..data.len() - 1];
+        let mut wrong_magic = data.clone();
+        wrong_magic[MAGIC.len() - 1] ^= 1;
+
+        let damaged_files = [
+            cut_in_the_nonce,
+            cut_in_the_text,
+            &wrong_magic[..MAGIC.len()],
+            &wrong_magic[..],
+        ];
+        for damaged in damaged_files {
+            fs::write(store.path(USER), damaged).unwrap();
+            assert!(store.load(USER).is_err());
+        }
+    }
+}
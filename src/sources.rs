@@ -0,0 +1,416 @@
+use crate::client_pool::ClientPool;
+use crate::common_utils::load_localization;
+use crate::news_block_creation_utils::get_dialogs;
+use crate::storage::Repository;
+use crate::telegram::telegram_request;
+use grammers_client::types;
+use grammers_tl_types as tl;
+use log::info;
+use serde_json::Value;
+use std::collections::HashMap;
+use teloxide::payloads::SendMessageSetters;
+use teloxide::prelude::{ChatId, Requester};
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, ParseMode};
+use teloxide::Bot;
+
+const PAGE_SIZE: usize = 8;
+const MAX_TITLE_LENGTH: usize = 40;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SourceKind {
+    Channel,
+    Group,
+    /// A supergroup with topics.
+    Forum,
+    PrivateChat,
+}
+
+impl SourceKind {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Channel => "channel",
+            Self::Group => "group",
+            Self::Forum => "forum",
+            Self::PrivateChat => "private",
+        }
+    }
+
+    pub(crate) fn from_str(value: &str) -> Option<Self> {
+        match value {
+            "channel" => Some(Self::Channel),
+            "group" => Some(Self::Group),
+            "forum" => Some(Self::Forum),
+            "private" => Some(Self::PrivateChat),
+            _ => None,
+        }
+    }
+
+    /// Everything but channels is people talking, which is summarized as a discussion, not as news.
+    pub(crate) fn is_discussion(&self) -> bool {
+        *self != Self::Channel
+    }
+
+    fn icon(&self) -> &'static str {
+        match self {
+            Self::Channel => "📢",
+            Self::Group => "👥",
+            Self::Forum => "🗂",
+            Self::PrivateChat => "👤",
+        }
+    }
+}
+
+/// A chat the user can pick as a podcast source, `chat_id` is the Telegram id of the chat.
+#[derive(Clone, Debug)]
+pub struct Source {
+    pub chat_id: i64,
+    pub title: String,
+    pub kind: SourceKind,
+    pub included: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct SourceSettings {
+    /// Whether channels the user subscribes to later go into the podcast without visiting /sources.
+    pub new_sources_included: bool,
+    pub include_groups: bool,
+    pub include_forums: bool,
+    /// Private chats are never included on their own, each one has to be picked in /sources.
+    pub include_private_chats: bool,
+}
+
+impl Default for SourceSettings {
+    fn default() -> Self {
+        Self {
+            new_sources_included: true,
+            include_groups: false,
+            include_forums: false,
+            include_private_chats: false,
+        }
+    }
+}
+
+impl SourceSettings {
+    pub(crate) fn is_enabled(&self, kind: SourceKind) -> bool {
+        match kind {
+            SourceKind::Channel => true,
+            SourceKind::Group => self.include_groups,
+            SourceKind::Forum => self.include_forums,
+            SourceKind::PrivateChat => self.include_private_chats,
+        }
+    }
+
+    fn toggle(&mut self, kind: SourceKind) {
+        match kind {
+            SourceKind::Channel => {}
+            SourceKind::Group => self.include_groups = !self.include_groups,
+            SourceKind::Forum => self.include_forums = !self.include_forums,
+            SourceKind::PrivateChat => self.include_private_chats = !self.include_private_chats,
+        }
+    }
+}
+
+/// Tells what kind of source the chat is, `None` for chats that never make it into a podcast
+/// (bots and "Saved Messages").
+pub(crate) fn source_kind(chat: &types::Chat) -> Option<SourceKind> {
+    match chat {
+        types::Chat::Channel(_) => Some(SourceKind::Channel),
+        types::Chat::Group(group) => match &group.raw {
+            tl::enums::Chat::Channel(channel) if channel.forum => Some(SourceKind::Forum),
+            _ => Some(SourceKind::Group),
+        },
+        types::Chat::User(user) if user.is_bot() || user.is_self() => None,
+        types::Chat::User(_) => Some(SourceKind::PrivateChat),
+    }
+}
+
+pub(crate) fn chat_title(chat: &types::Chat) -> String {
+    match chat {
+        types::Chat::User(user) => match (user.first_name(), user.last_name()) {
+            (first, Some(last)) => format!("{} {}", first, last),
+            (first, None) => first.to_string(),
+        },
+        _ => chat.name().to_string(),
+    }
+}
+
+/// Actions encoded in the inline keyboard buttons of the /sources picker.
+pub(crate) enum SourcesCallback {
+    Toggle { chat_id: i64, page: usize },
+    TogglePolicy { page: usize },
+    ToggleKind { kind: SourceKind, page: usize },
+    Page(usize),
+    Noop,
+}
+
+impl SourcesCallback {
+    const PREFIX: &'static str = "src";
+
+    fn encode(&self) -> String {
+        match self {
+            Self::Toggle { chat_id, page } => format!("{}:t:{}:{}", Self::PREFIX, chat_id, page),
+            Self::TogglePolicy { page } => format!("{}:n:{}", Self::PREFIX, page),
+            Self::ToggleKind { kind, page } => {
+                format!("{}:k:{}:{}", Self::PREFIX, kind.as_str(), page)
+            }
+            Self::Page(page) => format!("{}:p:{}", Self::PREFIX, page),
+            Self::Noop => format!("{}:x", Self::PREFIX),
+        }
+    }
+
+    pub(crate) fn decode(data: &str) -> Option<Self> {
+        let mut parts = data.split(':');
+        if parts.next()? != Self::PREFIX {
+            return None;
+        }
+
+        match parts.next()? {
+            "t" => Some(Self::Toggle {
+                chat_id: parts.next()?.parse().ok()?,
+                page: parts.next()?.parse().ok()?,
+            }),
+            "n" => Some(Self::TogglePolicy {
+                page: parts.next()?.parse().ok()?,
+            }),
+            "k" => Some(Self::ToggleKind {
+                kind: SourceKind::from_str(parts.next()?)?,
+                page: parts.next()?.parse().ok()?,
+            }),
+            "p" => Some(Self::Page(parts.next()?.parse().ok()?)),
+            "x" => Some(Self::Noop),
+            _ => None,
+        }
+    }
+}
+
+/// Records the chats seen for the first time according to the user's policy for new sources
+/// and refreshes the titles of the known ones, returns every source of the user.
+pub(crate) fn sync_sources(
+    storage: &dyn Repository,
+    user_id: u64,
+    dialogs: &[types::Dialog],
+) -> anyhow::Result<Vec<Source>> {
+    let settings = storage.load_source_settings(user_id)?;
+    let mut known: HashMap<i64, Source> = storage
+        .load_sources(user_id)?
+        .into_iter()
+        .map(|source| (source.chat_id, source))
+        .collect();
+
+    for dialog in dialogs {
+        let chat = dialog.chat();
+        let Some(kind) = source_kind(chat) else {
+            continue;
+        };
+        let title = chat_title(chat);
+
+        match known.get_mut(&chat.id()) {
+            Some(source) if source.title != title || source.kind != kind => {
+                source.title = title;
+                source.kind = kind;
+                storage.save_source(user_id, source)?;
+            }
+            Some(_) => {}
+            None => {
+                let source = Source {
+                    chat_id: chat.id(),
+                    title,
+                    kind,
+                    included: kind != SourceKind::PrivateChat && settings.new_sources_included,
+                };
+                info!(
+                    "New source {} (ID: {}), included: {}",
+                    source.title, source.chat_id, source.included
+                );
+                storage.save_source(user_id, &source)?;
+                known.insert(source.chat_id, source);
+            }
+        }
+    }
+
+    let mut sources: Vec<_> = known.into_values().collect();
+    sources.sort_by_key(|source| source.title.to_lowercase());
+    Ok(sources)
+}
+
+/// Keeps only the dialogs the user picked as podcast sources, of the kinds they turned on.
+pub(crate) fn select_sources(
+    storage: &dyn Repository,
+    user_id: u64,
+    dialogs: Vec<types::Dialog>,
+) -> anyhow::Result<Vec<types::Dialog>> {
+    let settings = storage.load_source_settings(user_id)?;
+    let included: Vec<i64> = sync_sources(storage, user_id, &dialogs)?
+        .into_iter()
+        .filter(|source| source.included && settings.is_enabled(source.kind))
+        .map(|source| source.chat_id)
+        .collect();
+
+    let selected: Vec<_> = dialogs
+        .into_iter()
+        .filter(|dialog| included.contains(&dialog.chat().id()))
+        .collect();
+    info!("{} sources selected for user {}", selected.len(), user_id);
+
+    Ok(selected)
+}
+
+pub(crate) async fn handle_sources_cmd(
+    bot: Bot,
+    chat_id: ChatId,
+    clients: &ClientPool,
+    storage: &dyn Repository,
+    language_code: &str,
+) -> anyhow::Result<()> {
+    let localization = load_localization(language_code);
+    let user_id = chat_id.0 as u64;
+
+    let client = clients.get(user_id).await?;
+    if !telegram_request!(true, client.is_authorized())? {
+        let message = localization["sources_cmd"]["not_authorized"]
+            .as_str()
+            .unwrap_or("Default message");
+        bot.send_message(chat_id, message).await?;
+        return Ok(());
+    }
+
+    let dialogs = get_dialogs(&client).await?;
+    let sources = sync_sources(storage, user_id, &dialogs)?;
+    let settings = storage.load_source_settings(user_id)?;
+
+    let message = localization["sources_cmd"]["title"]
+        .as_str()
+        .unwrap_or("Default message");
+    bot.send_message(chat_id, message)
+        .parse_mode(ParseMode::Html)
+        .reply_markup(sources_keyboard(&sources, &settings, 0, &localization))
+        .await?;
+
+    Ok(())
+}
+
+/// Applies a button press of the picker, returns the keyboard to show instead of the pressed one.
+pub(crate) fn apply_sources_callback(
+    storage: &dyn Repository,
+    user_id: u64,
+    callback: SourcesCallback,
+    language_code: &str,
+) -> anyhow::Result<Option<InlineKeyboardMarkup>> {
+    let page = match callback {
+        SourcesCallback::Toggle { chat_id, page } => {
+            if let Some(mut source) = storage
+                .load_sources(user_id)?
+                .into_iter()
+                .find(|source| source.chat_id == chat_id)
+            {
+                source.included = !source.included;
+                storage.save_source(user_id, &source)?;
+            }
+            page
+        }
+        SourcesCallback::TogglePolicy { page } => {
+            let mut settings = storage.load_source_settings(user_id)?;
+            settings.new_sources_included = !settings.new_sources_included;
+            storage.save_source_settings(user_id, &settings)?;
+            page
+        }
+        SourcesCallback::ToggleKind { kind, page } => {
+            let mut settings = storage.load_source_settings(user_id)?;
+            settings.toggle(kind);
+            storage.save_source_settings(user_id, &settings)?;
+            page
+        }
+        SourcesCallback::Page(page) => page,
+        SourcesCallback::Noop => return Ok(None),
+    };
+
+    let mut sources = storage.load_sources(user_id)?;
+    sources.sort_by_key(|source| source.title.to_lowercase());
+    let settings = storage.load_source_settings(user_id)?;
+    let localization = load_localization(language_code);
+
+    Ok(Some(sources_keyboard(&sources, &settings, page, &localization)))
+}
+
+fn sources_keyboard(
+    sources: &[Source],
+    settings: &SourceSettings,
+    page: usize,
+    localization: &Value,
+) -> InlineKeyboardMarkup {
+    let sources: Vec<_> = sources
+        .iter()
+        .filter(|source| settings.is_enabled(source.kind))
+        .collect();
+    // At least one page, the settings buttons are shown even without sources
+    let pages = sources.len().saturating_sub(1) / PAGE_SIZE + 1;
+    let page = page.min(pages - 1);
+
+    let mut rows: Vec<Vec<InlineKeyboardButton>> = sources
+        .iter()
+        .skip(page * PAGE_SIZE)
+        .take(PAGE_SIZE)
+        .map(|source| {
+            let mark = if source.included { "✅" } else { "⬜" };
+            let title: String = source.title.chars().take(MAX_TITLE_LENGTH).collect();
+            vec![InlineKeyboardButton::callback(
+                format!("{} {} {}", mark, source.kind.icon(), title),
+                SourcesCallback::Toggle {
+                    chat_id: source.chat_id,
+                    page,
+                }
+                .encode(),
+            )]
+        })
+        .collect();
+
+    if pages > 1 {
+        let mut navigation = Vec::new();
+        if page > 0 {
+            navigation.push(InlineKeyboardButton::callback(
+                "⬅️",
+                SourcesCallback::Page(page - 1).encode(),
+            ));
+        }
+        navigation.push(InlineKeyboardButton::callback(
+            format!("{}/{}", page + 1, pages),
+            SourcesCallback::Noop.encode(),
+        ));
+        if page + 1 < pages {
+            navigation.push(InlineKeyboardButton::callback(
+                "➡️",
+                SourcesCallback::Page(page + 1).encode(),
+            ));
+        }
+        rows.push(navigation);
+    }
+
+    let policy_key = if settings.new_sources_included {
+        "new_included"
+    } else {
+        "new_excluded"
+    };
+    rows.push(vec![InlineKeyboardButton::callback(
+        localization["sources_cmd"][policy_key]
+            .as_str()
+            .unwrap_or("Default message"),
+        SourcesCallback::TogglePolicy { page }.encode(),
+    )]);
+
+    for (kind, key) in [
+        (SourceKind::Group, "groups"),
+        (SourceKind::Forum, "forums"),
+        (SourceKind::PrivateChat, "private_chats"),
+    ] {
+        let mark = if settings.is_enabled(kind) { "✅" } else { "⬜" };
+        let label = localization["sources_cmd"][key]
+            .as_str()
+            .unwrap_or("Default message");
+        rows.push(vec![InlineKeyboardButton::callback(
+            format!("{} {} {}", mark, kind.icon(), label),
+            SourcesCallback::ToggleKind { kind, page }.encode(),
+        )]);
+    }
+
+    InlineKeyboardMarkup::new(rows)
+}
@@ -0,0 +1,379 @@
+use anyhow::{bail, Context, Result};
+use async_openai::types::{CreateSpeechRequestArgs, SpeechModel, Voice};
+use async_openai::Client as LLM_Client;
+use async_trait::async_trait;
+use log::info;
+use std::env;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+const SILENT_SAMPLE_RATE: u32 = 16000;
+/// The silent stub makes one second of audio for this many words, about the pace of a podcast.
+const SILENT_WORDS_PER_SECOND: usize = 3;
+
+/// Turns the podcast script into an audio file.
+#[async_trait]
+pub trait SpeechSynthesizer: Send + Sync {
+    /// Extension of the files the synthesizer writes, without the dot.
+    fn file_extension(&self) -> &'static str;
+
+    async fn synthesize(&self, text: &str, output: &Path) -> Result<()>;
+}
+
+pub(crate) struct OpenAiSpeech {
+    client: LLM_Client<async_openai::config::OpenAIConfig>,
+}
+
+impl OpenAiSpeech {
+    /// Uses the `OPENAI_API_KEY` env var.
+    pub(crate) fn new() -> Self {
+        Self {
+            client: LLM_Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl SpeechSynthesizer for OpenAiSpeech {
+    fn file_extension(&self) -> &'static str {
+        "mp3"
+    }
+
+    async fn synthesize(&self, text: &str, output: &Path) -> Result<()> {
+        let request = CreateSpeechRequestArgs::default()
+            .input(text)
+            .voice(Voice::Onyx)
+            .model(SpeechModel::Tts1Hd)
+            .speed(1.3)
+            .build()?;
+
+        let response = self.client.audio().speech(request).await?;
+        response.save(output).await?;
+        Ok(())
+    }
+}
+
+/// An offline engine run as a subprocess, which reads the text from stdin and writes a WAV file,
+/// e.g. `piper --model ru_RU-dmitri-medium.onnx --output_file {output}`.
+pub(crate) struct LocalSpeech {
+    program: String,
+    /// `{output}` is replaced with the path of the audio file.
+    args: Vec<String>,
+}
+
+impl LocalSpeech {
+    pub(crate) fn piper(program: String, model: String) -> Self {
+        Self {
+            program,
+            args: vec![
+                "--model".to_string(),
+                model,
+                "--output_file".to_string(),
+                "{output}".to_string(),
+            ],
+        }
+    }
+
+    pub(crate) fn espeak(program: String, voice: String) -> Self {
+        Self {
+            program,
+            args: vec![
+                "-v".to_string(),
+                voice,
+                "--stdin".to_string(),
+                "-w".to_string(),
+                "{output}".to_string(),
+            ],
+        }
+    }
+}
+
+#[async_trait]
+impl SpeechSynthesizer for LocalSpeech {
+    fn file_extension(&self) -> &'static str {
+        "wav"
+    }
+
+    async fn synthesize(&self, text: &str, output: &Path) -> Result<()> {
+        let output = output.to_string_lossy();
+        let args: Vec<String> = self
+            .args
+            .iter()
+            .map(|arg| arg.replace("{output}", &output))
+            .collect();
+
+        let mut child = Command::new(&self.program)
+            .args(&args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to start '{}'", self.program))?;
+
+        let mut stdin = child.stdin.take().context("No stdin for the speech engine")?;
+        let write = async move {
+            stdin.write_all(text.as_bytes()).await
+            // stdin is dropped here, the engine sees the end of the text
+        };
+
+        // stderr is read while the text is written, an engine that fills the stderr pipe before
+        // it has read all of its input would wait for us forever otherwise
+        let (written, result) = tokio::join!(write, child.wait_with_output());
+        let result = result?;
+        if !result.status.success() {
+            bail!(
+                "'{}' exited with {}: {}",
+                self.program,
+                result.status,
+                String::from_utf8_lossy(&result.stderr)
+            );
+        }
+        written?;
+        Ok(())
+    }
+}
+
+/// Writes silence as long as the text would take to read, so the pipeline can run without any
+/// speech engine.
+pub(crate) struct SilentSpeech;
+
+#[async_trait]
+impl SpeechSynthesizer for SilentSpeech {
+    fn file_extension(&self) -> &'static str {
+        "wav"
+    }
+
+    async fn synthesize(&self, text: &str, output: &Path) -> Result<()> {
+        let seconds = (text.split_whitespace().count() / SILENT_WORDS_PER_SECOND).max(1);
+        let output = output.to_path_buf();
+
+        // hound writes with blocking I/O
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let spec = hound::WavSpec {
+                channels: 1,
+                sample_rate: SILENT_SAMPLE_RATE,
+                bits_per_sample: 16,
+                sample_format: hound::SampleFormat::Int,
+            };
+
+            let mut writer = hound::WavWriter::create(output, spec)?;
+            for _ in 0..seconds as u32 * SILENT_SAMPLE_RATE {
+                writer.write_sample(0i16)?;
+            }
+            writer.finalize()?;
+            Ok(())
+        })
+        .await?
+    }
+}
+
+/// Picks the synthesizer with the `TTS_PROVIDER` env var: `openai` (default), `piper`, `espeak`
+/// or `silent`. `PIPER_PATH` and `PIPER_MODEL`, `ESPEAK_PATH` and `ESPEAK_VOICE` tune the local ones.
+pub(crate) fn speech_from_env() -> Result<Arc<dyn SpeechSynthesizer>> {
+    let kind = env::var("TTS_PROVIDER").unwrap_or_else(|_| "openai".to_string());
+
+    let speech: Arc<dyn SpeechSynthesizer> = match kind.as_str() {
+        "openai" => Arc::new(OpenAiSpeech::new()),
+        "piper" => Arc::new(LocalSpeech::piper(
+            env::var("PIPER_PATH").unwrap_or_else(|_| "piper".to_string()),
+            env::var("PIPER_MODEL").context("PIPER_MODEL must be set for the piper provider")?,
+        )),
+        "espeak" => Arc::new(LocalSpeech::espeak(
+            env::var("ESPEAK_PATH").unwrap_or_else(|_| "espeak-ng".to_string()),
+            env::var("ESPEAK_VOICE").unwrap_or_else(|_| "ru".to_string()),
+        )),
+        "silent" => Arc::new(SilentSpeech),
+        other => bail!("Unknown TTS_PROVIDER: {}", other),
+    };
+
+    info!("TTS provider: {}", kind);
+    Ok(speech)
+}
+
+/// Cuts the script into chunks of at most `max_chars` characters, at paragraph boundaries when
+/// possible, then at sentence and word boundaries.
+pub(crate) fn split_script(text: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+
+    for paragraph in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let mut separator = '\n';
+        for piece in split_to_fit(paragraph, max_chars) {
+            let length = current.chars().count() + 1 + piece.chars().count();
+            if !current.is_empty() && length > max_chars {
+                chunks.push(std::mem::take(&mut current));
+            }
+            if !current.is_empty() {
+                current.push(separator);
+            }
+            current.push_str(&piece);
+            separator = ' ';
+        }
+    }
+
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// Splits a paragraph too long for one chunk into sentences, and a sentence too long into words.
+fn split_to_fit(paragraph: &str, max_chars: usize) -> Vec<String> {
+    if paragraph.chars().count() <= max_chars {
+        return vec![paragraph.to_string()];
+    }
+
+    let sentences = split_sentences(paragraph);
+    if sentences.len() > 1 {
+        return sentences
+            .into_iter()
+            .flat_map(|sentence| split_to_fit(sentence, max_chars))
+            .collect();
+    }
+
+    paragraph
+        .split_whitespace()
+        .flat_map(|word| {
+            word.chars()
+                .collect::<Vec<_>>()
+                .chunks(max_chars)
+                .map(|part| part.iter().collect::<String>())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+fn split_sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((index, c)) = chars.next() {
+        let at_boundary = chars.peek().is_some_and(|(_, next)| next.is_whitespace());
+        if matches!(c, '.' | '!' | '?' | '…') && at_boundary {
+            let end = index + c.len_utf8();
+            sentences.push(text[start..end].trim());
+            start = end;
+        }
+    }
+    sentences.push(text[start..].trim());
+
+    sentences.retain(|sentence| !sentence.is_empty());
+    sentences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    /// A file in the temp dir that is removed when the test is over.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            Self(env::temp_dir().join(format!("news_wizard_{}_{}", std::process::id(), name)))
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn lengths(chunks: &[String]) -> Vec<usize> {
+        chunks.iter().map(|chunk| chunk.chars().count()).collect()
+    }
+
+    #[test]
+    fn split_script_keeps_short_paragraphs_together() {
+        let chunks = split_script("Привет!\n\nНовости дня.\nПока.", 100);
+
+        assert_eq!(chunks, vec!["Привет!\nНовости дня.\nПока."]);
+    }
+
+    #[test]
+    fn split_script_cuts_a_long_paragraph_at_sentences() {
+        let text = "Первое предложение. Второе предложение! Третье? Четвёртое…";
+
+        let chunks = split_script(text, 30);
+
+        assert_eq!(
+            chunks,
+            vec!["Первое предложение.", "Второе предложение! Третье?", "Четвёртое…"]
+        );
+    }
+
+    #[test]
+    fn split_script_cuts_text_without_punctuation_to_the_limit() {
+        let word = "а".repeat(25);
+        let text = vec![word.as_str(); 20].join(" ");
+
+        let chunks = split_script(&text, 60);
+
+        assert!(lengths(&chunks).iter().all(|length| *length <= 60));
+        assert_eq!(chunks.concat().replace(' ', ""), text.replace(' ', ""));
+        // A single word longer than the limit is cut too
+        let chunks = split_script(&"б".repeat(130), 60);
+        assert_eq!(lengths(&chunks), vec![60, 60, 10]);
+    }
+
+    #[test]
+    fn split_script_of_empty_text_is_empty() {
+        assert!(split_script("", 100).is_empty());
+        assert!(split_script(" \n\n  \n", 100).is_empty());
+    }
+
+    fn wav_seconds(path: &Path) -> u32 {
+        let reader = hound::WavReader::open(path).unwrap();
+        reader.duration() / reader.spec().sample_rate
+    }
+
+    #[tokio::test]
+    async fn silent_speech_lasts_as_long_as_the_words_take() {
+        let output = TempFile::new("silent.wav");
+        let text = vec!["word"; 30].join(" ");
+
+        SilentSpeech.synthesize(&text, &output.0).await.unwrap();
+
+        assert_eq!(wav_seconds(&output.0), 30 / SILENT_WORDS_PER_SECOND as u32);
+    }
+
+    #[tokio::test]
+    async fn silent_speech_lasts_at_least_a_second() {
+        let output = TempFile::new("silent_short.wav");
+
+        SilentSpeech.synthesize("Hi", &output.0).await.unwrap();
+
+        assert_eq!(wav_seconds(&output.0), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn local_speech_reads_stderr_while_writing_the_text() {
+        let output = TempFile::new("local.txt");
+        // Fills the stderr pipe before reading anything, then copies the text to the output
+        let engine = LocalSpeech {
+            program: "sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                "head -c 262144 /dev/zero >&2; cat > \"$0\"".to_string(),
+                "{output}".to_string(),
+            ],
+        };
+        let text = "слово ".repeat(50_000);
+
+        let synthesized =
+            tokio::time::timeout(Duration::from_secs(30), engine.synthesize(&text, &output.0))
+                .await
+                .expect("the engine and the bot wait for each other");
+
+        synthesized.unwrap();
+        assert_eq!(std::fs::read_to_string(&output.0).unwrap(), text);
+    }
+}
@@ -0,0 +1,612 @@
+use crate::scheduled_task::{
+    format_rules, parse_schedule_args, DeliveryRule, DeliverySchedule, DEFAULT_TIME_ZONE,
+};
+use crate::news_block_creation_utils::Lookback;
+use crate::queue::{JobStatus, PodcastJob};
+use crate::sources::{Source, SourceKind, SourceSettings};
+use crate::{AuthStages, UserData};
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use log::info;
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use teloxide::types::ChatId;
+
+// Every entry is applied once, in order; the index + 1 is stored as `PRAGMA user_version`.
+// Never edit an already released migration, append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE user_data (
+        user_id INTEGER PRIMARY KEY,
+        language_code TEXT
+    );
+    CREATE TABLE auth_stages (
+        user_id INTEGER PRIMARY KEY,
+        awaiting_phone_number INTEGER NOT NULL DEFAULT 0,
+        awaiting_passcode INTEGER NOT NULL DEFAULT 0,
+        awaiting_2fa INTEGER NOT NULL DEFAULT 0,
+        phone_number TEXT
+    );",
+    "CREATE TABLE schedules (
+        chat_id INTEGER PRIMARY KEY,
+        language_code TEXT NOT NULL,
+        hour INTEGER NOT NULL,
+        minute INTEGER NOT NULL
+    );",
+    "ALTER TABLE user_data ADD COLUMN time_zone TEXT;
+    ALTER TABLE user_data ADD COLUMN delivery_times TEXT NOT NULL DEFAULT '';
+    ALTER TABLE schedules ADD COLUMN time_zone TEXT NOT NULL DEFAULT 'Europe/Moscow';
+    ALTER TABLE schedules ADD COLUMN delivery_times TEXT NOT NULL DEFAULT '';
+    UPDATE schedules SET delivery_times = printf('%02d:%02d', hour, minute);
+    ALTER TABLE schedules DROP COLUMN hour;
+    ALTER TABLE schedules DROP COLUMN minute;",
+    "ALTER TABLE user_data RENAME COLUMN delivery_times TO delivery_rules;
+    ALTER TABLE schedules RENAME COLUMN delivery_times TO delivery_rules;",
+    "ALTER TABLE schedules ADD COLUMN paused_until TEXT;",
+    "CREATE TABLE sources (
+        user_id INTEGER NOT NULL,
+        chat_id INTEGER NOT NULL,
+        title TEXT NOT NULL,
+        included INTEGER NOT NULL,
+        PRIMARY KEY (user_id, chat_id)
+    );
+    CREATE TABLE source_settings (
+        user_id INTEGER PRIMARY KEY,
+        new_sources_included INTEGER NOT NULL DEFAULT 1
+    );",
+    "ALTER TABLE sources ADD COLUMN kind TEXT NOT NULL DEFAULT 'channel';
+    ALTER TABLE source_settings ADD COLUMN include_groups INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE source_settings ADD COLUMN include_forums INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE source_settings ADD COLUMN include_private_chats INTEGER NOT NULL DEFAULT 0;",
+    "CREATE TABLE last_seen (
+        user_id INTEGER NOT NULL,
+        chat_id INTEGER NOT NULL,
+        message_id INTEGER NOT NULL,
+        PRIMARY KEY (user_id, chat_id)
+    );",
+    "CREATE TABLE podcast_jobs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        chat_id INTEGER NOT NULL,
+        language_code TEXT NOT NULL,
+        time_zone TEXT NOT NULL,
+        lookback_hours INTEGER,
+        status TEXT NOT NULL,
+        error TEXT,
+        created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+    );",
+];
+
+pub trait Repository: Send + Sync {
+    fn load_user_data(&self) -> Result<HashMap<u64, UserData>>;
+    fn save_user_data(&self, user_id: u64, data: &UserData) -> Result<()>;
+    fn load_auth_stages(&self) -> Result<HashMap<u64, AuthStages>>;
+    fn save_auth_stages(&self, user_id: u64, state: &AuthStages) -> Result<()>;
+    fn delete_user(&self, user_id: u64) -> Result<()>;
+    fn load_schedules(&self) -> Result<Vec<DeliverySchedule>>;
+    fn save_schedule(&self, schedule: &DeliverySchedule) -> Result<()>;
+    fn delete_schedule(&self, chat_id: ChatId) -> Result<()>;
+    fn load_source_settings(&self, user_id: u64) -> Result<SourceSettings>;
+    fn save_source_settings(&self, user_id: u64, settings: &SourceSettings) -> Result<()>;
+    fn load_sources(&self, user_id: u64) -> Result<Vec<Source>>;
+    fn save_source(&self, user_id: u64, source: &Source) -> Result<()>;
+    /// Id of the newest message of each source that made it into a podcast, by chat id.
+    fn load_last_seen(&self, user_id: u64) -> Result<HashMap<i64, i32>>;
+    fn save_last_seen(&self, user_id: u64, chat_id: i64, message_id: i32) -> Result<()>;
+    /// Stores a new podcast job, returns its id.
+    fn insert_job(&self, job: &PodcastJob) -> Result<i64>;
+    fn save_job_status(&self, id: i64, status: JobStatus, error: Option<&str>) -> Result<()>;
+    /// Jobs that were queued or running when the bot stopped, oldest first.
+    fn load_unfinished_jobs(&self) -> Result<Vec<PodcastJob>>;
+    /// When the last podcast job of the chat was done, `None` if none was.
+    fn last_done_job_at(&self, chat_id: ChatId) -> Result<Option<DateTime<Utc>>>;
+}
+
+pub(crate) struct SqliteRepository {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteRepository {
+    /// Opens (or creates) the database at `path`, `":memory:"` gives a throwaway in-memory database.
+    pub(crate) fn open(path: &str) -> Result<Self> {
+        if let Some(parent) = Path::new(path).parent() {
+            if !parent.as_os_str().is_empty() && !parent.exists() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+
+        let mut connection = Connection::open(path)?;
+        run_migrations(&mut connection)?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.connection.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn rules_from_sql(value: String) -> Vec<DeliveryRule> {
+    parse_schedule_args(&value)
+        .map(|(rules, _)| rules)
+        .unwrap_or_default()
+}
+
+fn time_zone_from_sql(value: Option<String>) -> Option<Tz> {
+    value.and_then(|name| name.parse().ok())
+}
+
+/// A window is stored as its length in hours, `NULL` stands for `SinceLastSeen`.
+fn lookback_to_sql(lookback: Lookback) -> Option<i64> {
+    match lookback {
+        Lookback::SinceLastSeen => None,
+        Lookback::Window(period) => Some(period.num_hours()),
+    }
+}
+
+fn lookback_from_sql(value: Option<i64>) -> Lookback {
+    value.map_or(Lookback::SinceLastSeen, |hours| {
+        Lookback::Window(Duration::hours(hours))
+    })
+}
+
+fn run_migrations(connection: &mut Connection) -> Result<()> {
+    let current_version: usize =
+        connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(current_version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+        info!("Storage: migration {} applied", index + 1);
+    }
+
+    Ok(())
+}
+
+impl Repository for SqliteRepository {
+    fn load_user_data(&self) -> Result<HashMap<u64, UserData>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT user_id, language_code, time_zone, delivery_rules FROM user_data",
+        )?;
+
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)? as u64,
+                UserData {
+                    language_code: row.get(1)?,
+                    time_zone: time_zone_from_sql(row.get(2)?),
+                    delivery_rules: rules_from_sql(row.get(3)?),
+                },
+            ))
+        })?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn save_user_data(&self, user_id: u64, data: &UserData) -> Result<()> {
+        self.connection().execute(
+            "INSERT INTO user_data (user_id, language_code, time_zone, delivery_rules)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(user_id) DO UPDATE SET
+                language_code = excluded.language_code,
+                time_zone = excluded.time_zone,
+                delivery_rules = excluded.delivery_rules",
+            params![
+                user_id as i64,
+                data.language_code,
+                data.time_zone.map(|tz| tz.name()),
+                format_rules(&data.delivery_rules)
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn load_auth_stages(&self) -> Result<HashMap<u64, AuthStages>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT user_id, awaiting_phone_number, awaiting_passcode, awaiting_2fa, phone_number
+             FROM auth_stages",
+        )?;
+
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)? as u64,
+                AuthStages {
+                    awaiting_phone_number: row.get(1)?,
+                    awaiting_passcode: row.get(2)?,
+                    awaiting_2fa: row.get(3)?,
+                    phone_number: row.get(4)?,
+                    ..AuthStages::default()
+                },
+            ))
+        })?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn save_auth_stages(&self, user_id: u64, state: &AuthStages) -> Result<()> {
+        self.connection().execute(
+            "INSERT INTO auth_stages
+                (user_id, awaiting_phone_number, awaiting_passcode, awaiting_2fa, phone_number)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(user_id) DO UPDATE SET
+                awaiting_phone_number = excluded.awaiting_phone_number,
+                awaiting_passcode = excluded.awaiting_passcode,
+                awaiting_2fa = excluded.awaiting_2fa,
+                phone_number = excluded.phone_number",
+            params![
+                user_id as i64,
+                state.awaiting_phone_number,
+                state.awaiting_passcode,
+                state.awaiting_2fa,
+                state.phone_number
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn delete_user(&self, user_id: u64) -> Result<()> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM user_data WHERE user_id = ?1", params![user_id as i64])?;
+        transaction.execute("DELETE FROM auth_stages WHERE user_id = ?1", params![user_id as i64])?;
+        transaction.execute("DELETE FROM sources WHERE user_id = ?1", params![user_id as i64])?;
+        transaction.execute(
+            "DELETE FROM source_settings WHERE user_id = ?1",
+            params![user_id as i64],
+        )?;
+        transaction.execute("DELETE FROM last_seen WHERE user_id = ?1", params![user_id as i64])?;
+        transaction.execute("DELETE FROM podcast_jobs WHERE chat_id = ?1", params![user_id as i64])?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn load_schedules(&self) -> Result<Vec<DeliverySchedule>> {
+        let connection = self.connection();
+        let mut statement = connection
+            .prepare(
+            "SELECT chat_id, language_code, delivery_rules, time_zone, paused_until FROM schedules",
+        )?;
+
+        let rows = statement.query_map([], |row| {
+            Ok(DeliverySchedule {
+                chat_id: ChatId(row.get(0)?),
+                language_code: row.get(1)?,
+                rules: rules_from_sql(row.get(2)?),
+                time_zone: time_zone_from_sql(row.get(3)?).unwrap_or(DEFAULT_TIME_ZONE),
+                paused_until: row.get(4)?,
+            })
+        })?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn save_schedule(&self, schedule: &DeliverySchedule) -> Result<()> {
+        self.connection().execute(
+            "INSERT INTO schedules (chat_id, language_code, delivery_rules, time_zone, paused_until)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(chat_id) DO UPDATE SET
+                language_code = excluded.language_code,
+                delivery_rules = excluded.delivery_rules,
+                time_zone = excluded.time_zone,
+                paused_until = excluded.paused_until",
+            params![
+                schedule.chat_id.0,
+                schedule.language_code,
+                format_rules(&schedule.rules),
+                schedule.time_zone.name(),
+                schedule.paused_until
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn delete_schedule(&self, chat_id: ChatId) -> Result<()> {
+        self.connection()
+            .execute("DELETE FROM schedules WHERE chat_id = ?1", params![chat_id.0])?;
+        Ok(())
+    }
+
+    fn load_source_settings(&self, user_id: u64) -> Result<SourceSettings> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT new_sources_included, include_groups, include_forums, include_private_chats
+             FROM source_settings WHERE user_id = ?1",
+        )?;
+
+        let mut rows = statement.query_map(params![user_id as i64], |row| {
+            Ok(SourceSettings {
+                new_sources_included: row.get(0)?,
+                include_groups: row.get(1)?,
+                include_forums: row.get(2)?,
+                include_private_chats: row.get(3)?,
+            })
+        })?;
+
+        Ok(rows.next().transpose()?.unwrap_or_default())
+    }
+
+    fn save_source_settings(&self, user_id: u64, settings: &SourceSettings) -> Result<()> {
+        self.connection().execute(
+            "INSERT INTO source_settings
+                (user_id, new_sources_included, include_groups, include_forums, include_private_chats)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(user_id) DO UPDATE SET
+                new_sources_included = excluded.new_sources_included,
+                include_groups = excluded.include_groups,
+                include_forums = excluded.include_forums,
+                include_private_chats = excluded.include_private_chats",
+            params![
+                user_id as i64,
+                settings.new_sources_included,
+                settings.include_groups,
+                settings.include_forums,
+                settings.include_private_chats
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn load_sources(&self, user_id: u64) -> Result<Vec<Source>> {
+        let connection = self.connection();
+        let mut statement = connection
+            .prepare("SELECT chat_id, title, kind, included FROM sources WHERE user_id = ?1")?;
+
+        let rows = statement.query_map(params![user_id as i64], |row| {
+            Ok(Source {
+                chat_id: row.get(0)?,
+                title: row.get(1)?,
+                kind: SourceKind::from_str(&row.get::<_, String>(2)?).unwrap_or(SourceKind::Channel),
+                included: row.get(3)?,
+            })
+        })?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn save_source(&self, user_id: u64, source: &Source) -> Result<()> {
+        self.connection().execute(
+            "INSERT INTO sources (user_id, chat_id, title, kind, included) VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(user_id, chat_id) DO UPDATE SET
+                title = excluded.title,
+                kind = excluded.kind,
+                included = excluded.included",
+            params![
+                user_id as i64,
+                source.chat_id,
+                source.title,
+                source.kind.as_str(),
+                source.included
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn load_last_seen(&self, user_id: u64) -> Result<HashMap<i64, i32>> {
+        let connection = self.connection();
+        let mut statement =
+            connection.prepare("SELECT chat_id, message_id FROM last_seen WHERE user_id = ?1")?;
+
+        let rows = statement.query_map(params![user_id as i64], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn save_last_seen(&self, user_id: u64, chat_id: i64, message_id: i32) -> Result<()> {
+        self.connection().execute(
+            "INSERT INTO last_seen (user_id, chat_id, message_id) VALUES (?1, ?2, ?3)
+             ON CONFLICT(user_id, chat_id) DO UPDATE SET message_id = excluded.message_id",
+            params![user_id as i64, chat_id, message_id],
+        )?;
+        Ok(())
+    }
+
+    fn insert_job(&self, job: &PodcastJob) -> Result<i64> {
+        let connection = self.connection();
+        connection.execute(
+            "INSERT INTO podcast_jobs (chat_id, language_code, time_zone, lookback_hours, status)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                job.chat_id.0,
+                job.language_code,
+                job.time_zone.name(),
+                lookback_to_sql(job.lookback),
+                job.status.as_str()
+            ],
+        )?;
+        Ok(connection.last_insert_rowid())
+    }
+
+    fn save_job_status(&self, id: i64, status: JobStatus, error: Option<&str>) -> Result<()> {
+        self.connection().execute(
+            "UPDATE podcast_jobs SET status = ?2, error = ?3, updated_at = CURRENT_TIMESTAMP
+             WHERE id = ?1",
+            params![id, status.as_str(), error],
+        )?;
+        Ok(())
+    }
+
+    fn load_unfinished_jobs(&self) -> Result<Vec<PodcastJob>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT id, chat_id, language_code, time_zone, lookback_hours, status FROM podcast_jobs
+             WHERE status NOT IN ('done', 'failed', 'cancelled') ORDER BY id",
+        )?;
+
+        let rows = statement.query_map([], |row| {
+            Ok(PodcastJob {
+                id: row.get(0)?,
+                chat_id: ChatId(row.get(1)?),
+                language_code: row.get(2)?,
+                time_zone: time_zone_from_sql(row.get(3)?).unwrap_or(DEFAULT_TIME_ZONE),
+                lookback: lookback_from_sql(row.get(4)?),
+                status: JobStatus::from_str(&row.get::<_, String>(5)?).unwrap_or(JobStatus::Queued),
+            })
+        })?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn last_done_job_at(&self, chat_id: ChatId) -> Result<Option<DateTime<Utc>>> {
+        let done_at = self.connection().query_row(
+            "SELECT MAX(updated_at) FROM podcast_jobs WHERE chat_id = ?1 AND status = 'done'",
+            params![chat_id.0],
+            |row| row.get(0),
+        )?;
+        Ok(done_at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Europe::Berlin;
+
+    fn repository() -> SqliteRepository {
+        SqliteRepository::open(":memory:").unwrap()
+    }
+
+    #[test]
+    fn user_data_round_trip() {
+        let storage = repository();
+        let (delivery_rules, _) =
+            parse_schedule_args("weekdays 08:30 18:00; sun 10:00 recap").unwrap();
+        let data = UserData {
+            language_code: Some("ru".to_string()),
+            time_zone: Some(Berlin),
+            delivery_rules,
+        };
+
+        storage.save_user_data(42, &data).unwrap();
+        let loaded = storage.load_user_data().unwrap();
+
+        let user = &loaded[&42];
+        assert_eq!(user.language_code, data.language_code);
+        assert_eq!(user.time_zone, data.time_zone);
+        assert_eq!(user.delivery_rules, data.delivery_rules);
+    }
+
+    #[test]
+    fn user_data_is_updated_in_place() {
+        let storage = repository();
+        let mut data = UserData {
+            language_code: Some("en".to_string()),
+            ..UserData::default()
+        };
+        storage.save_user_data(42, &data).unwrap();
+
+        data.language_code = Some("ru".to_string());
+        storage.save_user_data(42, &data).unwrap();
+
+        let loaded = storage.load_user_data().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[&42].language_code.as_deref(), Some("ru"));
+        assert_eq!(loaded[&42].time_zone, None);
+        assert!(loaded[&42].delivery_rules.is_empty());
+    }
+
+    #[test]
+    fn auth_stages_round_trip() {
+        let storage = repository();
+        let state = AuthStages {
+            awaiting_passcode: true,
+            phone_number: Some("+10000000000".to_string()),
+            passcode: Some("12345".to_string()),
+            ..AuthStages::default()
+        };
+
+        storage.save_auth_stages(7, &state).unwrap();
+        let loaded = storage.load_auth_stages().unwrap();
+
+        let stages = &loaded[&7];
+        assert!(!stages.awaiting_phone_number);
+        assert!(stages.awaiting_passcode);
+        assert!(!stages.awaiting_2fa);
+        assert_eq!(stages.phone_number, state.phone_number);
+        // The code is only good for one login attempt, it isn't kept
+        assert_eq!(stages.passcode, None);
+    }
+
+    #[test]
+    fn delete_user_removes_only_that_user() {
+        let storage = repository();
+        for user_id in [1, 2] {
+            storage.save_user_data(user_id, &UserData::default()).unwrap();
+            storage
+                .save_auth_stages(user_id, &AuthStages::default())
+                .unwrap();
+            storage.save_last_seen(user_id, -100, 10).unwrap();
+        }
+
+        storage.delete_user(1).unwrap();
+
+        let user_data = storage.load_user_data().unwrap();
+        assert!(!user_data.contains_key(&1));
+        assert!(user_data.contains_key(&2));
+        let auth_stages = storage.load_auth_stages().unwrap();
+        assert!(!auth_stages.contains_key(&1));
+        assert!(auth_stages.contains_key(&2));
+        assert!(storage.load_last_seen(1).unwrap().is_empty());
+        assert_eq!(storage.load_last_seen(2).unwrap()[&-100], 10);
+    }
+
+    #[test]
+    fn last_done_job_at_skips_unfinished_and_failed_jobs() {
+        let storage = repository();
+        let job = |chat_id| PodcastJob {
+            id: 0,
+            chat_id: ChatId(chat_id),
+            language_code: "en".to_string(),
+            time_zone: Berlin,
+            lookback: Lookback::SinceLastSeen,
+            status: JobStatus::Queued,
+        };
+        assert_eq!(storage.last_done_job_at(ChatId(1)).unwrap(), None);
+
+        let failed = storage.insert_job(&job(1)).unwrap();
+        storage.save_job_status(failed, JobStatus::Failed, Some("boom")).unwrap();
+        storage.insert_job(&job(1)).unwrap();
+        let other_chat = storage.insert_job(&job(2)).unwrap();
+        storage.save_job_status(other_chat, JobStatus::Done, None).unwrap();
+        assert_eq!(storage.last_done_job_at(ChatId(1)).unwrap(), None);
+
+        let done = storage.insert_job(&job(1)).unwrap();
+        storage.save_job_status(done, JobStatus::Done, None).unwrap();
+        let done_at = storage.last_done_job_at(ChatId(1)).unwrap().unwrap();
+        assert!((Utc::now() - done_at).num_seconds().abs() < 60);
+    }
+
+    #[test]
+    fn migrations_run_twice_are_applied_once() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run_migrations(&mut connection).unwrap();
+        connection
+            .execute(
+                "INSERT INTO user_data (user_id, language_code) VALUES (1, 'en')",
+                [],
+            )
+            .unwrap();
+
+        run_migrations(&mut connection).unwrap();
+
+        let version: usize = connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        let language_code: String = connection
+            .query_row("SELECT language_code FROM user_data WHERE user_id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(language_code, "en");
+    }
+}
@@ -0,0 +1,216 @@
+use crate::common_utils::positive_from_env;
+use grammers_client::InvocationError;
+use lazy_static::lazy_static;
+use log::info;
+use rand::Rng;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::{sleep_until, Instant};
+
+/// Telegram requests made for all users together, per second.
+const DEFAULT_TELEGRAM_REQUESTS_PER_SECOND: u32 = 10;
+const MAX_ATTEMPTS: u32 = 5;
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// A longer FLOOD_WAIT isn't waited out, the source is given up on instead.
+const MAX_FLOOD_WAIT: Duration = Duration::from_secs(300);
+/// Messages and dialogs the grammers iterators fetch in one request, the rest of a chunk comes
+/// from their local buffer.
+pub(crate) const ITER_CHUNK_SIZE: usize = 100;
+
+lazy_static! {
+    /// The moment the next Telegram request may go out.
+    static ref NEXT_REQUEST: Mutex<Instant> = Mutex::new(Instant::now());
+    static ref REQUEST_INTERVAL: Duration = {
+        let per_second = positive_from_env(
+            "TELEGRAM_REQUESTS_PER_SECOND",
+            DEFAULT_TELEGRAM_REQUESTS_PER_SECOND,
+        );
+        Duration::from_secs(1) / per_second
+    };
+}
+
+/// Runs a Telegram request through the global rate limit, retrying it after FLOOD_WAIT and
+/// transient errors; the request expression is evaluated again for every attempt. The first
+/// attempt waits for the rate limit only if `$throttled` holds, the retries always do.
+macro_rules! telegram_request {
+    ($throttled:expr, $request:expr) => {{
+        let throttled = $throttled;
+        let mut attempt = 0;
+        loop {
+            if throttled || attempt > 0 {
+                $crate::telegram::throttle().await;
+            }
+            match $request.await {
+                Ok(value) => break Ok(value),
+                Err(e) => {
+                    attempt += 1;
+                    match $crate::telegram::retry_delay(&e, attempt) {
+                        Some(delay) => tokio::time::sleep(delay).await,
+                        None => break Err(e),
+                    }
+                }
+            }
+        }
+    }};
+}
+pub(crate) use telegram_request;
+
+/// Takes the next item of a grammers iterator, `$taken` counts the items taken so far. Only the
+/// item that starts a new chunk goes through the rate limit, the others don't reach Telegram.
+macro_rules! telegram_next {
+    ($iter:ident, $taken:ident) => {{
+        let chunk_due = $taken % $crate::telegram::ITER_CHUNK_SIZE == 0;
+        $taken += 1;
+        $crate::telegram::telegram_request!(chunk_due, $iter.next())
+    }};
+}
+pub(crate) use telegram_next;
+
+#[derive(Debug, PartialEq)]
+enum ErrorKind {
+    /// Telegram told how long to wait before the next request.
+    FloodWait(Duration),
+    /// Network trouble or a hiccup on the Telegram side, worth another try.
+    Transient,
+    /// Trying again won't help, e.g. CHANNEL_PRIVATE.
+    Fatal,
+}
+
+fn classify(error: &InvocationError) -> ErrorKind {
+    match error {
+        InvocationError::Rpc(rpc) => match rpc.name.as_str() {
+            "FLOOD_WAIT" | "FLOOD_PREMIUM_WAIT" | "SLOWMODE_WAIT" => {
+                ErrorKind::FloodWait(Duration::from_secs(rpc.value.unwrap_or(1) as u64))
+            }
+            _ if rpc.code >= 500 || rpc.code == -503 => ErrorKind::Transient,
+            "RPC_CALL_FAIL" | "TIMEOUT" => ErrorKind::Transient,
+            _ => ErrorKind::Fatal,
+        },
+        InvocationError::Io(_) | InvocationError::Dropped | InvocationError::Transport(_) => {
+            ErrorKind::Transient
+        }
+        _ => ErrorKind::Fatal,
+    }
+}
+
+/// Waits for the turn of the request, so that all clients together stay under the limit.
+///
+/// The login calls and sign out only wait here and aren't retried: their errors aren't plain
+/// `InvocationError`s, a repeated code request voids the code the user is typing, and the user
+/// sees the failure at once and can simply try again.
+pub(crate) async fn throttle() {
+    let slot = {
+        let mut next_request = NEXT_REQUEST.lock().await;
+        let slot = (*next_request).max(Instant::now());
+        *next_request = slot + *REQUEST_INTERVAL;
+        slot
+    };
+    sleep_until(slot).await;
+}
+
+/// How long to wait before the attempt after `attempt` failed ones, `None` to give up.
+pub(crate) fn retry_delay(error: &InvocationError, attempt: u32) -> Option<Duration> {
+    if attempt >= MAX_ATTEMPTS {
+        info!("Telegram request failed {} times, giving up: {}", attempt, error);
+        return None;
+    }
+
+    match classify(error) {
+        ErrorKind::FloodWait(wait) if wait <= MAX_FLOOD_WAIT => {
+            info!("FLOOD_WAIT: waiting {} seconds", wait.as_secs());
+            // A second on top, the wait is rounded down to whole seconds
+            Some(wait + Duration::from_secs(1))
+        }
+        ErrorKind::FloodWait(wait) => {
+            info!("FLOOD_WAIT of {} seconds is too long, giving up", wait.as_secs());
+            None
+        }
+        ErrorKind::Transient => {
+            let backoff = (BASE_BACKOFF * 2u32.pow(attempt - 1)).min(MAX_BACKOFF);
+            let jitter = rand::thread_rng().gen_range(0..=backoff.as_millis() as u64 / 2);
+            let delay = backoff + Duration::from_millis(jitter);
+            info!(
+                "Telegram request failed ({}), retrying in {} ms",
+                error,
+                delay.as_millis()
+            );
+            Some(delay)
+        }
+        ErrorKind::Fatal => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use grammers_client::RpcError;
+    use std::io;
+
+    fn rpc(code: i32, name: &str, value: Option<u32>) -> InvocationError {
+        InvocationError::Rpc(RpcError {
+            code,
+            name: name.to_string(),
+            value,
+            caused_by: None,
+        })
+    }
+
+    fn io_error() -> InvocationError {
+        InvocationError::Io(io::Error::new(io::ErrorKind::ConnectionReset, "reset"))
+    }
+
+    #[test]
+    fn flood_wait_takes_the_seconds_telegram_asked_for() {
+        assert_eq!(
+            classify(&rpc(420, "FLOOD_WAIT", Some(7))),
+            ErrorKind::FloodWait(Duration::from_secs(7))
+        );
+        assert_eq!(
+            classify(&rpc(420, "SLOWMODE_WAIT", None)),
+            ErrorKind::FloodWait(Duration::from_secs(1))
+        );
+        assert_eq!(
+            retry_delay(&rpc(420, "FLOOD_WAIT", Some(7)), 1),
+            Some(Duration::from_secs(8))
+        );
+    }
+
+    #[test]
+    fn too_long_flood_wait_is_given_up_on() {
+        let seconds = MAX_FLOOD_WAIT.as_secs() as u32 + 1;
+
+        assert_eq!(retry_delay(&rpc(420, "FLOOD_WAIT", Some(seconds)), 1), None);
+    }
+
+    #[test]
+    fn transient_errors_back_off_exponentially_with_jitter() {
+        for attempt in 1..MAX_ATTEMPTS {
+            let backoff = (BASE_BACKOFF * 2u32.pow(attempt - 1)).min(MAX_BACKOFF);
+            for error in [io_error(), InvocationError::Dropped, rpc(500, "INTERNAL", None)] {
+                let delay = retry_delay(&error, attempt).unwrap();
+                assert!(delay >= backoff && delay <= backoff * 3 / 2, "{:?}", delay);
+            }
+        }
+    }
+
+    #[test]
+    fn retries_stop_after_max_attempts() {
+        assert_eq!(retry_delay(&io_error(), MAX_ATTEMPTS), None);
+        assert_eq!(retry_delay(&rpc(420, "FLOOD_WAIT", Some(1)), MAX_ATTEMPTS), None);
+    }
+
+    #[test]
+    fn request_errors_are_fatal() {
+        for error in [
+            rpc(400, "CHANNEL_PRIVATE", None),
+            rpc(401, "AUTH_KEY_UNREGISTERED", None),
+            rpc(403, "CHAT_WRITE_FORBIDDEN", None),
+        ] {
+            assert_eq!(classify(&error), ErrorKind::Fatal);
+            assert_eq!(retry_delay(&error, 1), None);
+        }
+        assert_eq!(classify(&rpc(-503, "TIMEOUT", None)), ErrorKind::Transient);
+        assert_eq!(classify(&rpc(400, "RPC_CALL_FAIL", None)), ErrorKind::Transient);
+    }
+}
@@ -0,0 +1,25 @@
+use lazy_static::lazy_static;
+use tiktoken_rs::{o200k_base, CoreBPE};
+
+lazy_static! {
+    /// The tokenizer of the gpt-4o family, close enough for other models to plan prompt sizes.
+    static ref TOKENIZER: CoreBPE = o200k_base().expect("Failed to load the o200k_base tokenizer");
+}
+
+pub(crate) fn count_tokens(text: &str) -> usize {
+    TOKENIZER.encode_with_special_tokens(text).len()
+}
+
+/// Keeps the first `max_tokens` tokens of the text.
+pub(crate) fn truncate_to_tokens(text: &str, max_tokens: usize) -> String {
+    let tokens = TOKENIZER.encode_with_special_tokens(text);
+    if tokens.len() <= max_tokens {
+        return text.to_string();
+    }
+
+    // A multibyte character can be split between tokens, then a few tokens less are kept
+    (max_tokens.saturating_sub(3)..=max_tokens)
+        .rev()
+        .find_map(|length| TOKENIZER.decode(tokens[..length].to_vec()).ok())
+        .unwrap_or_default()
+}